@@ -3,6 +3,10 @@ local M = {}
 M.config = {
   host = "127.0.0.1",
   port = 6666,
+  -- Set to the server's --auth-token, if it was started with one
+  auth_token = nil,
+  -- Show lines crow prints on its own, e.g. from a metro, as notifications
+  show_events = true,
   keymaps = {
     sendExpr = "<leader>ce",
     sendBuffer = "<leader>ca",
@@ -16,6 +20,9 @@ M.config = {
   }
 }
 
+-- The server protocol this plugin speaks
+local PROTOCOL_VERSION = 1
+
 ---@type Client | nil
 local client = nil
 
@@ -71,12 +78,23 @@ local function handleResponse(data)
   if not data then return end
 
   if data.status == 'Success' then
+    local request_info = pending_requests[data.request_id]
+
+    if request_info then
+      -- A response can run over several lines, they're shown together once it's Done
+      request_info.lines = request_info.lines or {}
+      table.insert(request_info.lines, data.contents)
+    else
+      -- No context, just show notification
+      vim.notify('result: ' .. data.contents, vim.log.levels.INFO)
+    end
+  elseif data.status == 'Done' then
     local request_id = data.request_id
     local request_info = pending_requests[request_id]
 
     if request_info then
       pending_requests[request_id] = nil
-      local result_str = data.contents
+      local result_str = table.concat(request_info.lines or {}, '\n')
 
       -- Show inline if we have context
       if request_info.bufnr and request_info.line then
@@ -87,9 +105,6 @@ local function handleResponse(data)
       if not M.config.inline_results.enabled or #result_str < 20 then
         vim.notify('result: ' .. result_str, vim.log.levels.INFO)
       end
-    else
-      -- No context, just show notification
-      vim.notify('result: ' .. data.contents, vim.log.levels.INFO)
     end
   elseif data.status == 'Failure' then
     local request_id = data.request_id
@@ -111,6 +126,16 @@ local function handleResponse(data)
     vim.notify('error: ' .. error_msg, vim.log.levels.ERROR)
   elseif data.status == 'Affirm' then
     vim.notify('server ready', vim.log.levels.INFO)
+  elseif data.status == 'Event' then
+    -- Not a reply to anything we sent, so there's no request to attach it to
+    if M.config.show_events then
+      vim.notify('crow: ' .. data.contents, vim.log.levels.INFO)
+    end
+  elseif data.status == 'Ping' then
+    -- The server's heartbeat, a session that doesn't answer it is dropped
+    if client then
+      client:send({ status = 'Pong' })
+    end
   end
 end
 
@@ -168,9 +193,14 @@ function M.connect()
       vim.notify('failed to connect to pidgeon: ' .. err, vim.log.levels.ERROR)
       client = nil
     else
+      if M.config.auth_token then
+        client:send({ status = 'Auth', token = M.config.auth_token })
+      end
+
       -- Send Start message to initiate session
       client:send({
-        status = 'Start'
+        status = 'Start',
+        version = PROTOCOL_VERSION,
       }, function(start_err)
         if start_err then
           vim.notify('failed to start session: ' .. start_err, vim.log.levels.ERROR)
@@ -218,8 +248,13 @@ function M.check()
     if err then
       vim.notify('failed to connect for check: ' .. err, vim.log.levels.ERROR)
     else
+      if M.config.auth_token then
+        check_client:send({ status = 'Auth', token = M.config.auth_token })
+      end
+
       check_client:send({
-        status = 'Check'
+        status = 'Check',
+        version = PROTOCOL_VERSION,
       }, function(send_err)
         if send_err then
           vim.notify('failed to send check: ' .. send_err, vim.log.levels.ERROR)
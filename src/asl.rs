@@ -0,0 +1,154 @@
+use clap::Subcommand;
+
+const SHAPES: [&str; 9] = [
+    "linear",
+    "sine",
+    "logarithmic",
+    "exponential",
+    "now",
+    "wait",
+    "over",
+    "under",
+    "rebound",
+];
+
+// Crow's outputs span -5V to +10V
+fn level(s: &str) -> Result<f64, String> {
+    let volts: f64 = s.parse().map_err(|_| format!("invalid voltage '{s}'"))?;
+    if (-5.0..=10.0).contains(&volts) {
+        Ok(volts)
+    } else {
+        Err(format!("{volts}V is outside crow's -5V to 10V range"))
+    }
+}
+
+fn positive(s: &str) -> Result<f64, String> {
+    let value: f64 = s.parse().map_err(|_| format!("invalid number '{s}'"))?;
+    if value > 0.0 {
+        Ok(value)
+    } else {
+        Err(format!("{value} must be greater than 0"))
+    }
+}
+
+fn unit(s: &str) -> Result<f64, String> {
+    let value: f64 = s.parse().map_err(|_| format!("invalid number '{s}'"))?;
+    if (0.0..=1.0).contains(&value) {
+        Ok(value)
+    } else {
+        Err(format!("{value} must be between 0 and 1"))
+    }
+}
+
+// Each action maps onto one of crow's ASL constructors, assigned to an output and started:
+//   output[n].action = <constructor>(...); output[n]()
+#[derive(Debug, Clone, Subcommand)]
+pub enum Action {
+    /// Free-running LFO: lfo(time, level, shape)
+    Lfo {
+        #[arg(value_parser = clap::value_parser!(u8).range(1..=4))]
+        output: u8,
+        /// Cycles per second
+        #[arg(value_parser = positive)]
+        freq: f64,
+        /// Peak voltage
+        #[arg(value_parser = level, default_value_t = 5.0)]
+        amp: f64,
+        #[arg(long, default_value = "sine", value_parser = SHAPES)]
+        shape: String,
+    },
+    /// Audio-rate oscillator: oscillate(freq, level, shape)
+    Oscillate {
+        #[arg(value_parser = clap::value_parser!(u8).range(1..=4))]
+        output: u8,
+        /// Hz
+        #[arg(value_parser = positive)]
+        freq: f64,
+        #[arg(value_parser = level, default_value_t = 5.0)]
+        level: f64,
+        #[arg(long, default_value = "sine", value_parser = SHAPES)]
+        shape: String,
+    },
+    /// Single pulse: pulse(time, level, polarity)
+    Pulse {
+        #[arg(value_parser = clap::value_parser!(u8).range(1..=4))]
+        output: u8,
+        /// Seconds the pulse stays high
+        #[arg(value_parser = positive)]
+        time: f64,
+        #[arg(value_parser = level, default_value_t = 5.0)]
+        level: f64,
+        /// Pulse low instead of high
+        #[arg(long)]
+        negative: bool,
+    },
+    /// Repeating ramp: ramp(time, skew, level)
+    Ramp {
+        #[arg(value_parser = clap::value_parser!(u8).range(1..=4))]
+        output: u8,
+        /// Seconds per cycle
+        #[arg(value_parser = positive)]
+        time: f64,
+        /// 0 is a falling saw, 1 is a rising saw
+        #[arg(value_parser = unit, default_value_t = 0.5)]
+        skew: f64,
+        #[arg(value_parser = level, default_value_t = 5.0)]
+        level: f64,
+    },
+    /// Attack/release envelope: ar(attack, release, level)
+    Ar {
+        #[arg(value_parser = clap::value_parser!(u8).range(1..=4))]
+        output: u8,
+        /// Seconds
+        #[arg(value_parser = positive)]
+        attack: f64,
+        /// Seconds
+        #[arg(value_parser = positive)]
+        release: f64,
+        #[arg(value_parser = level, default_value_t = 7.0)]
+        level: f64,
+    },
+}
+
+impl Action {
+    pub fn output(&self) -> u8 {
+        match self {
+            Action::Lfo { output, .. }
+            | Action::Oscillate { output, .. }
+            | Action::Pulse { output, .. }
+            | Action::Ramp { output, .. }
+            | Action::Ar { output, .. } => *output,
+        }
+    }
+
+    fn constructor(&self) -> String {
+        match self {
+            Action::Lfo {
+                freq, amp, shape, ..
+            } => format!("lfo({}, {amp}, '{shape}')", 1.0 / freq),
+            Action::Oscillate {
+                freq, level, shape, ..
+            } => format!("oscillate({freq}, {level}, '{shape}')"),
+            Action::Pulse {
+                time,
+                level,
+                negative,
+                ..
+            } => format!("pulse({time}, {level}, {})", if *negative { -1 } else { 1 }),
+            Action::Ramp {
+                time, skew, level, ..
+            } => format!("ramp({time}, {skew}, {level})"),
+            Action::Ar {
+                attack,
+                release,
+                level,
+                ..
+            } => format!("ar({attack}, {release}, {level})"),
+        }
+    }
+
+    pub fn lua(&self) -> String {
+        let n = self.output();
+        format!("output[{n}].action = {}; output[{n}]()", self.constructor())
+    }
+}
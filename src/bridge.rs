@@ -0,0 +1,48 @@
+use super::{crow::Crow, error::*, event::CrowEvent};
+use std::time::Duration;
+use tracing::info;
+
+// A software patch cable: input `from_input` on one crow drives output `to_output` on another.
+//
+// Each value takes one stream interval to be sampled, then a USB round trip to reach the
+// destination, so expect roughly the interval plus a few ms of latency and steps at the interval's
+// rate. Good for modulation, not for audio
+pub async fn run(
+    source: Crow,
+    mut destination: Crow,
+    from_input: u8,
+    to_output: u8,
+    interval: Duration,
+) -> Result<()> {
+    let (mut reader, mut writer) = source.split();
+
+    writer
+        .write_all(&format!(
+            "input[{from_input}].mode('stream', {})",
+            interval.as_secs_f64()
+        ))
+        .await?;
+    info!(target: "pidgeon::serial", "Streaming input {from_input} every {interval:?}");
+
+    loop {
+        let line = tokio::select! {
+            line = reader.read_once() => line?,
+            _ = tokio::signal::ctrl_c() => break,
+        };
+
+        match CrowEvent::parse(&line) {
+            CrowEvent::Stream { channel, volts } if channel == from_input => {
+                destination
+                    .write_all(&format!("output[{to_output}].volts = {volts}"))
+                    .await?;
+            }
+            CrowEvent::Line(line) => info!(target: "pidgeon::serial", "Source crow said: '{line}'"),
+            _ => {}
+        }
+    }
+
+    // Leave the source input how we found it
+    writer
+        .write_all(&format!("input[{from_input}].mode('none')"))
+        .await
+}
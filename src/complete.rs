@@ -0,0 +1,202 @@
+use rustyline::completion::Pair;
+
+// Crow's globals, what Tab offers at the start of a name
+const GLOBALS: &[&str] = &[
+    "output",
+    "input",
+    "ii",
+    "metro",
+    "public",
+    "clock",
+    "cal",
+    "asl",
+    "sequins",
+    "timeline",
+    "hotswap",
+    "print",
+    "tell",
+    "quote",
+    "delay",
+    "once",
+    "lfo",
+    "pulse",
+    "ar",
+    "adsr",
+    "oscillate",
+    "note",
+    "to",
+    "loop",
+    "held",
+    "lock",
+    "times",
+    "dyn",
+    "linlin",
+    "justvolts",
+    "just12",
+    "hztovolts",
+    "unique_id",
+    "time",
+    "cputime",
+    "init",
+    "step",
+    "collectgarbage",
+];
+
+// Members of each namespace, with any index written as []
+const MEMBERS: &[(&str, &[&str])] = &[
+    (
+        "output[]",
+        &[
+            "volts", "slew", "shape", "action", "scale", "done", "clock", "dyn", "query", "receive",
+        ],
+    ),
+    (
+        "input[]",
+        &[
+            "volts", "mode", "query", "stream", "change", "window", "scale", "volume", "peak",
+            "freq", "clock",
+        ],
+    ),
+    ("metro", &["init", "free_all"]),
+    ("metro[]", &["start", "stop", "time", "count", "event"]),
+    (
+        "clock",
+        &[
+            "run",
+            "cancel",
+            "sleep",
+            "sync",
+            "tempo",
+            "cleanup",
+            "transport",
+            "get_beats",
+            "get_tempo",
+        ],
+    ),
+    ("clock.transport", &["start", "stop"]),
+    (
+        "ii",
+        &[
+            "help", "pullup", "event", "raw", "jf", "txi", "txo", "ansible", "kria", "er301",
+            "wsyn", "wdel", "wtape", "disting", "faders", "crow",
+        ],
+    ),
+    (
+        "ii.jf",
+        &[
+            "trigger",
+            "play_note",
+            "play_voice",
+            "mode",
+            "run_mode",
+            "run",
+            "transpose",
+        ],
+    ),
+    ("public", &["add", "view", "discover", "update"]),
+    ("cal", &["test", "print", "default"]),
+    (
+        "sequins",
+        &["new", "step", "every", "count", "times", "select", "reset"],
+    ),
+];
+
+// What Tab offers for the name ending at `pos`, from the hardcoded lists above, and where in the
+// line it starts
+pub fn complete(line: &str, pos: usize) -> (usize, Vec<Pair>) {
+    let start = line[..pos]
+        .char_indices()
+        .rev()
+        .find(|&(_, c)| !(c.is_ascii_alphanumeric() || "_.[]".contains(c)))
+        .map_or(0, |(at, c)| at + c.len_utf8());
+    let word = &line[start..pos];
+
+    let (base, partial, names) = match word.rsplit_once('.') {
+        Some((base, partial)) => {
+            let shape = unindexed(base);
+            let Some((_, names)) = MEMBERS.iter().find(|(namespace, _)| *namespace == shape) else {
+                return (start, vec![]);
+            };
+            (Some(base), partial, *names)
+        }
+        None => (None, word, GLOBALS),
+    };
+
+    let candidates = names
+        .iter()
+        .filter(|name| name.starts_with(partial))
+        .map(|name| Pair {
+            display: name.to_string(),
+            replacement: match base {
+                Some(base) => format!("{base}.{name}"),
+                None => name.to_string(),
+            },
+        })
+        .collect();
+
+    (start, candidates)
+}
+
+// `output[1]` and `output[4]` have the same members, so the index is dropped before looking them up
+fn unindexed(base: &str) -> String {
+    let mut shape = String::with_capacity(base.len());
+    let mut indexed = false;
+
+    for c in base.chars() {
+        match c {
+            '[' => {
+                indexed = true;
+                shape.push(c);
+            }
+            ']' => {
+                indexed = false;
+                shape.push(c);
+            }
+            _ if indexed => {}
+            _ => shape.push(c),
+        }
+    }
+
+    shape
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replacements(line: &str) -> (usize, Vec<String>) {
+        let (start, pairs) = complete(line, line.len());
+        (
+            start,
+            pairs.into_iter().map(|pair| pair.replacement).collect(),
+        )
+    }
+
+    #[test]
+    fn globals() {
+        assert_eq!(replacements("met"), (0, vec!["metro".into()]));
+        assert_eq!(replacements("x = seq"), (4, vec!["sequins".into()]));
+        assert_eq!(replacements("zzz"), (0, vec![]));
+    }
+
+    #[test]
+    fn members() {
+        let (start, names) = replacements("output[1].vo");
+        assert_eq!(start, 0);
+        assert_eq!(names, ["output[1].volts"]);
+
+        let (start, names) = replacements("x = ii.jf.tr");
+        assert_eq!(start, 4);
+        assert_eq!(names, ["ii.jf.trigger", "ii.jf.transpose"]);
+
+        assert_eq!(replacements("nothing.he"), (0, vec![]));
+    }
+
+    // The word starts after a multibyte character, not partway into it
+    #[test]
+    fn non_ascii_prefix() {
+        let line = "print(\"café";
+        assert_eq!(complete(line, line.len()).0, line.len());
+        assert_eq!(replacements("é(pri"), (3, vec!["print".into()]));
+    }
+}
@@ -0,0 +1,160 @@
+use super::{error::*, output::ColorChoice};
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+// $XDG_CONFIG_HOME/pidgeon, falling back to ~/.config/pidgeon
+pub fn config_dir() -> Option<PathBuf> {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|dir| dir.join("pidgeon"))
+}
+
+// Where the repl keeps its history, $XDG_DATA_HOME/pidgeon/history or ~/.pidgeon_history
+pub fn history_path() -> Option<PathBuf> {
+    std::env::var_os("XDG_DATA_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| PathBuf::from(dir).join("pidgeon").join("history"))
+        .or_else(|| {
+            std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".pidgeon_history"))
+        })
+}
+
+// Durations are a number with an optional unit of ms, s or m, seconds if there's no unit
+pub fn parse_duration(s: &str) -> std::result::Result<Duration, String> {
+    let (value, unit) = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .map(|idx| s.split_at(idx))
+        .unwrap_or((s, "s"));
+
+    let value: f64 = value
+        .parse()
+        .map_err(|_| format!("invalid duration '{s}'"))?;
+
+    let secs = match unit {
+        "ms" => value / 1000.,
+        "s" => value,
+        "m" => value * 60.,
+        _ => return Err(format!("unknown unit '{unit}', expected ms, s or m")),
+    };
+
+    Duration::try_from_secs_f64(secs).map_err(|_| format!("duration '{s}' is out of range"))
+}
+
+// For a duration something repeats on, where zero would never let anything else run
+pub fn parse_period(s: &str) -> std::result::Result<Duration, String> {
+    match parse_duration(s)? {
+        Duration::ZERO => Err(format!("period '{s}' has to be longer than zero")),
+        period => Ok(period),
+    }
+}
+
+fn duration<'de, D>(deserializer: D) -> std::result::Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| parse_duration(&s).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+// config.toml holds named bundles of connection settings, picked with --profile:
+//
+//   [profile.live]
+//   idle_timeout = "100ms"
+//   strip_echo = true
+//
+//   [profile.ci]
+//   response_timeout = "5s"
+//   color = "never"
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub profile: BTreeMap<String, Profile>,
+}
+
+// Each field mirrors the global flag of the same name
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub strip_echo: Option<bool>,
+    pub half_duplex: Option<bool>,
+    pub delimit_threshold: Option<usize>,
+    pub baud: Option<u32>,
+    pub max_reconnect_attempts: Option<u32>,
+    pub reconnect_backoff_ms: Option<u64>,
+    pub detect_timeout_ms: Option<u64>,
+    #[serde(default, deserialize_with = "duration")]
+    pub idle_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "duration")]
+    pub response_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "duration")]
+    pub reply_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "duration")]
+    pub poll_timeout: Option<Duration>,
+    pub color: Option<ColorChoice>,
+}
+
+pub fn config_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join("config.toml"))
+}
+
+pub fn load() -> Result<Config> {
+    let Some(path) = config_path() else {
+        return Ok(Config::default());
+    };
+
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => Ok(toml::from_str(&contents)?),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+pub fn profile(name: &str) -> Result<Profile> {
+    let mut config = load()?;
+
+    config
+        .profile
+        .remove(name)
+        .ok_or_else(|| Error::UnknownProfile {
+            name: name.to_string(),
+            available: if config.profile.is_empty() {
+                "none defined".to_string()
+            } else {
+                config
+                    .profile
+                    .keys()
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            },
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("1.5"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_duration("0"), Ok(Duration::ZERO));
+        assert!(parse_duration("1h").is_err());
+        assert!(parse_duration("ms").is_err());
+        // Parses to infinity rather than failing to parse
+        assert!(parse_duration(&"9".repeat(400)).is_err());
+    }
+
+    #[test]
+    fn periods() {
+        assert_eq!(parse_period("1s"), Ok(Duration::from_secs(1)));
+        assert!(parse_period("0").is_err());
+        assert!(parse_period("0ms").is_err());
+    }
+}
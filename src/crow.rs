@@ -1,136 +1,1503 @@
-use super::error::*;
-use futures::StreamExt;
+use super::{error::*, event::CrowEvent, mock};
+use clap::ValueEnum;
+use futures::{Stream, StreamExt};
+use regex::Regex;
+use serde::Serialize;
+use std::sync::{Arc, LazyLock};
 use std::time::Duration;
-use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader, ReadHalf, WriteHalf};
-use tokio_serial::{SerialPortBuilderExt, SerialPortInfo, SerialPortType, SerialStream};
-use tokio_util::codec::{FramedRead, LinesCodec};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt, BufReader, ReadHalf, WriteHalf};
+use tokio::sync::{Mutex, Notify, OwnedMutexGuard, mpsc::UnboundedSender};
+use tokio::task::JoinHandle;
+use tokio_serial::{
+    SerialPort, SerialPortBuilderExt, SerialPortInfo, SerialPortType, SerialStream,
+};
+use tokio_util::bytes::BytesMut;
+use tokio_util::codec::{Decoder, FramedRead, LinesCodec, LinesCodecError};
 use tracing::{error, info};
 
-pub struct Crow(SerialStream);
+pub const RESPONSE_TIMEOUT: Duration = Duration::from_millis(200);
+// How long try_read_line waits for a line that isn't there yet
+pub const POLL_TIMEOUT: Duration = Duration::from_millis(10);
+const DRAIN_LIMIT: usize = 1000;
+pub const DETECT_TIMEOUT: Duration = Duration::from_secs(2);
+// How often `wait_for` looks again for a crow that isn't there yet
+const WAIT_INTERVAL: Duration = Duration::from_millis(250);
+
+// Current firmware talks at 115200, older bootloaders and custom builds may not
+pub const DEFAULT_BAUD: u32 = 115_200;
+pub const STANDARD_BAUDS: [u32; 11] = [
+    1200, 2400, 4800, 9600, 19200, 38400, 57600, 115_200, 230_400, 460_800, 921_600,
+];
+
+// Crow's REPL reads a line into a 64 byte buffer, anything longer has to go between ``` fences.
+// Counted in bytes, not chars, since that's what the buffer holds. Firmware with a different
+// buffer can be matched with `set_delimit_threshold`, 0 fences everything
+pub const LINE_LIMIT: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReadTimeouts {
+    // A gap this long between lines means the crow is done talking
+    pub idle: Duration,
+    // Hard cap on the whole response, including the wait for its first line
+    pub response: Duration,
+}
+
+impl Default for ReadTimeouts {
+    fn default() -> Self {
+        Self {
+            idle: Duration::from_millis(300),
+            response: Duration::from_secs(2),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadEnd {
+    Idle,
+    Deadline,
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub lines: Vec<String>,
+    pub ended: ReadEnd,
+}
+
+// Anything crow output can be read from a line at a time
+pub trait LineSource {
+    fn next_line(&mut self) -> impl Future<Output = Result<String>>;
+}
+
+// Collect lines until the crow goes idle or the response deadline passes, whichever comes first.
+// The first line may take up to the whole deadline to arrive, later ones only `idle`
+pub async fn read_response<S: LineSource>(
+    source: &mut S,
+    timeouts: ReadTimeouts,
+    mut on_line: impl FnMut(&str),
+) -> Result<Response> {
+    let deadline = tokio::time::Instant::now() + timeouts.response;
+    let mut lines = Vec::new();
+
+    loop {
+        let gap = if lines.is_empty() {
+            deadline
+        } else {
+            (tokio::time::Instant::now() + timeouts.idle).min(deadline)
+        };
+
+        match tokio::time::timeout_at(gap, source.next_line()).await {
+            Ok(line) => {
+                let line = line?.trim_end().to_string();
+                on_line(&line);
+                lines.push(line);
+            }
+            Err(_) => {
+                let ended = if tokio::time::Instant::now() >= deadline {
+                    ReadEnd::Deadline
+                } else {
+                    ReadEnd::Idle
+                };
+                info!(target: "pidgeon::serial", "Response ended by {ended:?} after {} line(s)", lines.len());
+
+                return Ok(Response { lines, ended });
+            }
+        }
+    }
+}
+
+// The product string crow reports over USB, the surest way to tell it apart
+pub const CROW_PRODUCT: &str = "crow: telephone line";
+// Crow enumerates as the STM32 virtual COM port. Other STM32 boards share these IDs, so they're only
+// a fallback for when the OS doesn't report crow's product string
+pub const CROW_VID: u16 = 0x0483;
+pub const CROW_PID: u16 = 0x5740;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Heuristic {
+    Product,
+    VidPid,
+}
+
+pub fn crow_match(port: &SerialPortInfo) -> Option<Heuristic> {
+    match &port.port_type {
+        SerialPortType::UsbPort(info)
+            if info.product.as_ref().is_some_and(|s| s == CROW_PRODUCT) =>
+        {
+            Some(Heuristic::Product)
+        }
+        SerialPortType::UsbPort(info) if info.vid == CROW_VID && info.pid == CROW_PID => {
+            Some(Heuristic::VidPid)
+        }
+        _ => None,
+    }
+}
+
+// How an upload went, judged from what the crow printed after it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Upload {
+    // The crow said it's running the script, `lines` is everything it printed up to that point
+    Running { lines: Vec<String> },
+    CompileError(String),
+    // Nothing conclusive before the deadline, the script may still be fine, e.g. firmware that
+    // doesn't announce uploads
+    Unconfirmed { lines: Vec<String> },
+}
+
+// Lines crow prints once an uploaded script is loaded, matched case-insensitively as prefixes
+const UPLOAD_ACKS: [&str; 2] = ["running", "user script"];
+
+// Read the crow's output after an upload until it confirms the script is running, reports a
+// compile error, or `timeout` passes
+pub async fn confirm_upload<S: LineSource>(source: &mut S, timeout: Duration) -> Result<Upload> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut lines = Vec::new();
+
+    loop {
+        let line = match tokio::time::timeout_at(deadline, source.next_line()).await {
+            Ok(line) => line?.trim_end().to_string(),
+            Err(_) => return Ok(Upload::Unconfirmed { lines }),
+        };
+
+        if is_compile_error(&line) {
+            return Ok(Upload::CompileError(line));
+        }
+
+        let lower = line.trim().to_lowercase();
+        let acked = UPLOAD_ACKS.iter().any(|ack| lower.starts_with(ack));
+        lines.push(line);
+
+        if acked {
+            return Ok(Upload::Running { lines });
+        }
+    }
+}
+
+pub fn is_crow(port: &SerialPortInfo) -> bool {
+    crow_match(port).is_some()
+}
+
+// Product string matches come first, they're the ones we're sure of
+fn crows(ports: &[SerialPortInfo]) -> Vec<(Heuristic, &SerialPortInfo)> {
+    let mut crows = ports
+        .iter()
+        .filter_map(|port| crow_match(port).map(|heuristic| (heuristic, port)))
+        .collect::<Vec<_>>();
+    crows.sort_by_key(|(heuristic, _)| *heuristic != Heuristic::Product);
+
+    crows
+}
+
+// Lua reports a chunk that fails to compile as `<chunk>:<line>: <reason>`. Runtime errors share that
+// prefix, so only reasons the parser produces count as compile errors:
+//   - `syntax error ...`, `unexpected symbol near ...`
+//   - `'<token>' expected near ...` (e.g. `'end' expected near <eof>`)
+//   - `unfinished string/long string/long comment ...`
+//   - `malformed number near ...`
+static COMPILE_ERROR: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"^.*:\d+: (syntax error|unexpected symbol|'.+' expected|unfinished (string|long string|long comment)|malformed number)",
+    )
+    .expect("valid regex")
+});
+
+pub fn is_compile_error(line: &str) -> bool {
+    COMPILE_ERROR.is_match(line.trim())
+}
+
+// A row in `pidgeon list`/`doctor`, any serial device the OS knows about
+#[derive(Debug, Clone, Serialize)]
+pub struct PortEntry {
+    pub path: String,
+    pub kind: &'static str,
+    pub product: Option<String>,
+    pub serial: Option<String>,
+    pub crow: bool,
+    // Position among connected crows, for --index
+    pub index: Option<usize>,
+}
+
+impl From<&SerialPortInfo> for PortEntry {
+    fn from(port: &SerialPortInfo) -> Self {
+        let (kind, product, serial) = match &port.port_type {
+            SerialPortType::UsbPort(info) => {
+                ("usb", info.product.clone(), info.serial_number.clone())
+            }
+            SerialPortType::PciPort => ("pci", None, None),
+            SerialPortType::BluetoothPort => ("bluetooth", None, None),
+            SerialPortType::Unknown => ("unknown", None, None),
+        };
+
+        Self {
+            path: port.port_name.clone(),
+            kind,
+            product,
+            serial,
+            crow: is_crow(port),
+            index: None,
+        }
+    }
+}
+
+pub fn ports() -> Result<Vec<PortEntry>> {
+    let ports = tokio_serial::available_ports()?;
+    let order = crows(&ports);
+
+    Ok(ports
+        .iter()
+        .map(|port| PortEntry {
+            index: order
+                .iter()
+                .position(|(_, crow)| crow.port_name == port.port_name),
+            ..PortEntry::from(port)
+        })
+        .collect())
+}
+
+// The device a `Crow` was opened on, USB details are only known when the OS reports them
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ConnectInfo {
+    pub path: String,
+    pub product: Option<String>,
+    pub serial: Option<String>,
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+    pub baud: u32,
+    // Opened by path rather than found by detection, reconnects reopen the same path
+    pub explicit: bool,
+}
+
+impl From<&SerialPortInfo> for ConnectInfo {
+    fn from(port: &SerialPortInfo) -> Self {
+        match &port.port_type {
+            SerialPortType::UsbPort(info) => Self {
+                path: port.port_name.clone(),
+                product: info.product.clone(),
+                serial: info.serial_number.clone(),
+                vid: Some(info.vid),
+                pid: Some(info.pid),
+                baud: DEFAULT_BAUD,
+                explicit: false,
+            },
+            _ => Self {
+                path: port.port_name.clone(),
+                baud: DEFAULT_BAUD,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl ConnectInfo {
+    fn lookup(path: &str) -> Self {
+        tokio_serial::available_ports()
+            .ok()
+            .and_then(|ports| {
+                ports
+                    .iter()
+                    .find(|port| port.port_name == path)
+                    .map(ConnectInfo::from)
+            })
+            .unwrap_or_else(|| Self {
+                path: path.into(),
+                baud: DEFAULT_BAUD,
+                ..Default::default()
+            })
+    }
+}
+
+// Which crow detection should pick when more than one is plugged in
+#[derive(Debug, Clone, Default)]
+pub enum Select {
+    // Whichever crow is connected, an error if there's more than one
+    #[default]
+    Only,
+    // Position in `Crow::list`
+    Index(usize),
+    Serial(String),
+}
+
+// What to leave the crow doing when a session ends. Drop can't await a write, so nothing is sent
+// unless `shutdown` is called
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Teardown {
+    /// ^^c, clear whatever was uploaded or typed in
+    Clear,
+    /// ^^r, restart the script stored in flash
+    Restart,
+}
+
+impl Teardown {
+    fn command(self) -> &'static [u8] {
+        match self {
+            Teardown::Clear => b"^^c",
+            Teardown::Restart => b"^^r",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReconnectPolicy {
+    // 0 retries forever
+    pub max_attempts: u32,
+    // Wait after the first failed attempt, doubling after each one up to `max_backoff`
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 10,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+// Keep calling `connect` until it works, backing off between tries so a crow that's still
+// re-enumerating isn't hammered. Giving up reports the last attempt's error
+pub async fn retry<T, F, Fut>(policy: ReconnectPolicy, mut connect: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 0;
+    let mut backoff = policy.backoff;
+    loop {
+        attempt += 1;
+
+        match connect().await {
+            Ok(connected) => {
+                // Working first time isn't news
+                if attempt > 1 {
+                    info!(target: "pidgeon::serial", "Reconnected after {attempt} attempts");
+                }
+                return Ok(connected);
+            }
+            Err(err) if policy.max_attempts != 0 && attempt >= policy.max_attempts => {
+                error!(target: "pidgeon::serial", "Reconnect attempt {attempt} failed: {err}, giving up");
+                return Err(Error::ReconnectFailed {
+                    attempts: attempt,
+                    last: Box::new(err),
+                });
+            }
+            Err(err) => {
+                info!(target: "pidgeon::serial",
+                    "Reconnect attempt {attempt} failed: {err}, retrying in {backoff:?}"
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(policy.max_backoff);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CrowIdentity {
+    pub unique_id: String,
+    // Crow only says whether it's calibrated as it boots, so this is None unless that line came
+    // through while waiting on the query, e.g. right after a restart
+    pub calibrated: Option<bool>,
+}
+
+// Boot lines like "Calibration loaded" or "Using default calibration"
+fn calibration(line: &str) -> Option<bool> {
+    let line = line.to_lowercase();
+    if !line.contains("calibrat") {
+        return None;
+    }
+
+    Some(
+        !["default", "not ", "uncalibrated", "no calibration"]
+            .iter()
+            .any(|missing| line.contains(missing)),
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct Acked {
+    pub lines: Vec<String>,
+    // False if the deadline passed before the crow got through the command
+    pub acked: bool,
+}
+
+type Port = BufReader<SerialStream>;
+
+pub struct Crow {
+    // One buffered reader for the life of the connection, bytes read past a newline stay buffered
+    // for the next line instead of being dropped with a throwaway reader
+    port: Port,
+    // A line still being read when the last read timed out
+    partial: Vec<u8>,
+    info: ConnectInfo,
+    // Some serial setups echo everything sent, drop a reply that's just our own command
+    strip_echo: bool,
+    half_duplex: bool,
+    // Lines longer than this many bytes are sent fenced
+    delimit_threshold: usize,
+    // Wait for a single reply line, and for a line that may or may not be coming
+    response_timeout: Duration,
+    poll_timeout: Duration,
+}
 
 impl Crow {
     pub fn new() -> Result<Self> {
+        Crow::new_with_baud(DEFAULT_BAUD)
+    }
+
+    pub fn new_with_baud(baud: u32) -> Result<Self> {
+        Crow::find(&Select::Only, baud)
+    }
+
+    // Every connected crow, ones matched by their Product string before ones matched only by
+    // VID/PID, otherwise in the order the OS lists them. `--index` counts in this order
+    pub fn list() -> Result<Vec<ConnectInfo>> {
         let ports = tokio_serial::available_ports()?;
 
-        info!("Ports: {:?}", &ports);
+        info!(target: "pidgeon::serial", "Ports: {:?}", &ports);
+
+        Ok(crows(&ports)
+            .into_iter()
+            .map(|(heuristic, port)| {
+                info!(target: "pidgeon::serial", "{} matched as a crow by {heuristic:?}", port.port_name);
+                ConnectInfo::from(port)
+            })
+            .collect())
+    }
+
+    pub fn find(select: &Select, baud: u32) -> Result<Self> {
+        let crows = Crow::list()?;
+
+        let info = match select {
+            Select::Only => match crows.as_slice() {
+                [] => return Err(Error::NotFound(None)),
+                [crow] => crow.clone(),
+                crows => {
+                    return Err(Error::AmbiguousCrow(
+                        crows
+                            .iter()
+                            .map(|crow| crow.path.as_str())
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                    ));
+                }
+            },
+            Select::Index(index) => crows
+                .get(*index)
+                .cloned()
+                .ok_or_else(|| Error::NotFound(Some(format!("index {index}"))))?,
+            Select::Serial(serial) => crows
+                .into_iter()
+                .find(|crow| crow.serial.as_ref() == Some(serial))
+                .ok_or_else(|| Error::NotFound(Some(format!("serial number {serial}"))))?,
+        };
+
+        info!(target: "pidgeon::serial", "Found crow: {}", info.path);
+        Crow::open_with_info(info, baud)
+    }
+
+    // Keep looking until a crow shows up or `wait` passes, for starting up alongside a crow that's
+    // still enumerating. Anything but not finding one fails straight away
+    pub async fn wait_for(
+        wait: Duration,
+        detect_timeout: Duration,
+        select: Select,
+        baud: u32,
+    ) -> Result<Self> {
+        let deadline = tokio::time::Instant::now() + wait;
 
-        let crow = ports.iter().find_map(|port| match port {
-            SerialPortInfo {
-                port_name,
-                port_type: SerialPortType::UsbPort(info),
-            } if info
-                .product
-                .as_ref()
-                .is_some_and(|s| s == "crow: telephone line") =>
-            {
-                Some(port_name)
+        loop {
+            match Crow::detect(detect_timeout, select.clone(), baud).await {
+                Err(Error::NotFound(_)) if tokio::time::Instant::now() < deadline => {
+                    info!(target: "pidgeon::serial", "No crow yet, looking again in {WAIT_INTERVAL:?}");
+                    tokio::time::sleep(WAIT_INTERVAL).await;
+                }
+                res => return res,
             }
-            _ => None,
-        });
+        }
+    }
 
-        match crow {
-            Some(path) => {
-                info!("Found crow: {}", path);
+    // USB serial numbers stay put across reboots and replugs, unlike port paths
+    pub fn open_by_serial(serial: &str) -> Result<Self> {
+        Crow::find(&Select::Serial(serial.to_string()), DEFAULT_BAUD)
+    }
 
-                let port = tokio_serial::new(path, 115_200).open_native_async()?;
+    // Like `find`, but gives up if the OS takes longer than `timeout` to list ports, which a wedged
+    // serial driver can make it do indefinitely
+    pub async fn detect(timeout: Duration, select: Select, baud: u32) -> Result<Self> {
+        let detect = tokio::task::spawn_blocking(move || Crow::find(&select, baud));
 
-                Ok(Crow(port))
+        match tokio::time::timeout(timeout, detect).await {
+            Ok(joined) => joined.map_err(std::io::Error::other)?,
+            Err(_) => {
+                error!(target: "pidgeon::serial", "Port enumeration timed out after {timeout:?}");
+                Err(Error::DetectTimeout(timeout))
             }
-            None => Err(Error::NotFound),
         }
     }
 
-    pub(crate) fn mock(stream: SerialStream) -> Self {
-        Self(stream)
+    // Skip detection and open a known device path
+    pub fn open(path: &str) -> Result<Self> {
+        Crow::with_baud(path, DEFAULT_BAUD)
+    }
+
+    pub fn with_baud(path: &str, baud: u32) -> Result<Self> {
+        let info = ConnectInfo {
+            explicit: true,
+            ..ConnectInfo::lookup(path)
+        };
+
+        Crow::open_with_info(info, baud)
+    }
+
+    fn open_with_info(mut info: ConnectInfo, baud: u32) -> Result<Self> {
+        if !STANDARD_BAUDS.contains(&baud) {
+            return Err(Error::InvalidBaud(baud));
+        }
+
+        let port = tokio_serial::new(&info.path, baud).open_native_async()?;
+        info.baud = baud;
+
+        Ok(Crow::from_port(port, info))
+    }
+
+    pub fn info(&self) -> &ConnectInfo {
+        &self.info
+    }
+
+    // Re-run detection until a crow shows up again, e.g. after the cable was jiggled
+    pub async fn reconnect(&mut self, policy: ReconnectPolicy) -> Result<()> {
+        let crow = Crow::reopen(&self.info, policy).await?;
+        self.port = crow.port;
+        self.partial.clear();
+        self.info = crow.info;
+
+        Ok(())
+    }
+
+    // Get back a crow like the one `info` describes, at the same speed and the same path if it
+    // was given explicitly
+    pub async fn reopen(info: &ConnectInfo, policy: ReconnectPolicy) -> Result<Self> {
+        retry(policy, || async {
+            match (&info.serial, info.explicit) {
+                (_, true) => Crow::with_baud(&info.path, info.baud),
+                (Some(serial), false) => {
+                    Crow::detect(DETECT_TIMEOUT, Select::Serial(serial.clone()), info.baud).await
+                }
+                (None, false) => Crow::detect(DETECT_TIMEOUT, Select::Only, info.baud).await,
+            }
+        })
+        .await
+    }
+
+    fn from_port(port: SerialStream, info: ConnectInfo) -> Self {
+        Self {
+            port: BufReader::new(port),
+            partial: Vec::new(),
+            info,
+            strip_echo: false,
+            half_duplex: false,
+            delimit_threshold: LINE_LIMIT,
+            response_timeout: RESPONSE_TIMEOUT,
+            poll_timeout: POLL_TIMEOUT,
+        }
+    }
+
+    pub fn mock(stream: SerialStream) -> Self {
+        Self::from_port(
+            stream,
+            ConnectInfo {
+                path: "mock".into(),
+                baud: DEFAULT_BAUD,
+                ..Default::default()
+            },
+        )
+    }
+
+    // A crow on a pty pair, played by `mock::MockCrow`: ^^ commands, ^^s...^^e/^^w uploads and
+    // ``` fences are answered the way crow would, and `print` of a string or some arithmetic is
+    // evaluated. Other Lua prints nothing
+    pub fn simulated() -> Result<(Self, JoinHandle<()>)> {
+        let (leader, follower) = SerialStream::pair()?;
+
+        let handle = tokio::spawn(mock::serve(follower));
+
+        Ok((Self::mock(leader), handle))
+    }
+
+    pub fn set_strip_echo(&mut self, strip_echo: bool) {
+        self.strip_echo = strip_echo;
+    }
+
+    // Never read while a write is in flight, for adapters that garble replies arriving right
+    // after a send
+    pub fn set_half_duplex(&mut self, half_duplex: bool) {
+        self.half_duplex = half_duplex;
+    }
+
+    pub fn set_delimit_threshold(&mut self, threshold: usize) {
+        self.delimit_threshold = threshold;
+    }
+
+    // Slow links, like a crow behind a USB hub, can take longer than the defaults to start
+    // answering
+    pub fn set_reply_timeouts(&mut self, response: Duration, poll: Duration) {
+        self.response_timeout = response;
+        self.poll_timeout = poll;
+    }
+
+    // DTR and RTS are left as the OS opened them unless set here. Crow doesn't need either, it
+    // gets into its bootloader with ^^b, but some hubs and CDC drivers hold data back until DTR is
+    // raised, and dropping it then raising it again can shake loose a port that's stopped talking
+    pub fn set_dtr(&mut self, level: bool) -> Result<()> {
+        Ok(self.port.get_mut().write_data_terminal_ready(level)?)
+    }
+
+    pub fn set_rts(&mut self, level: bool) -> Result<()> {
+        Ok(self.port.get_mut().write_request_to_send(level)?)
     }
 
     pub fn split(self) -> (CrowReader, CrowWriter) {
-        let (reader, writer) = tokio::io::split(self.0);
-        let reader = FramedRead::new(reader, LinesCodec::new());
+        let control = self
+            .port
+            .get_ref()
+            .try_clone()
+            .inspect_err(
+                |err| error!(target: "pidgeon::serial", "No handle on the control lines: {err}"),
+            )
+            .ok();
+        let (reader, writer) = tokio::io::split(self.port);
+        let mut reader = FramedRead::new(reader, CrowCodec::default());
+        reader.read_buffer_mut().extend_from_slice(&self.partial);
+        let half_duplex = self.half_duplex.then(HalfDuplex::default);
+
+        (
+            CrowReader(reader, half_duplex.clone(), self.info.clone()),
+            CrowWriter {
+                port: writer,
+                half_duplex,
+                info: self.info,
+                delimit_threshold: self.delimit_threshold,
+                control,
+            },
+        )
+    }
+
+    // Reads only ever follow writes here, so half duplex just needs the write to drain first
+    async fn settle(&mut self) -> Result<()> {
+        if self.half_duplex {
+            self.port.flush().await.map_err(Error::serial)?;
+            tokio::time::sleep(HALF_DUPLEX_SETTLE).await;
+        }
 
-        (CrowReader(reader), CrowWriter(writer))
+        Ok(())
     }
 
     pub async fn write_delimited(&mut self, chunk: &str) -> Result<()> {
-        write_delimited(&mut self.0, chunk.as_bytes()).await
+        write_delimited(&mut self.port, chunk.as_bytes()).await?;
+        self.settle().await
     }
 
     pub async fn write_script(&mut self, chunk: &str) -> Result<()> {
-        write_script(&mut self.0, chunk.as_bytes()).await
+        write_script(&mut self.port, chunk.as_bytes()).await?;
+        self.settle().await
     }
 
     pub async fn write_all(&mut self, chunk: &str) -> Result<()> {
-        write_all(&mut self.0, chunk.as_bytes()).await
+        write_all(&mut self.port, chunk.as_bytes()).await?;
+        self.settle().await
+    }
+
+    // ^^b reboots the crow into its bootloader, no control line juggling needed. The serial port
+    // goes away with it and crow comes back as a DFU device, so the port vanishing mid-flush
+    // means it worked
+    pub async fn enter_bootloader(&mut self) -> Result<()> {
+        let sent = async {
+            write_all(&mut self.port, b"^^b").await?;
+            self.port.flush().await.map_err(Error::serial)
+        };
+
+        match sent.await {
+            Err(Error::Disconnected(_)) => Ok(()),
+            res => res,
+        }
+    }
+
+    // ^^c clears the user script, crow falls back to its default blank script
+    pub async fn clear_script(&mut self) -> Result<()> {
+        self.write_all("^^c").await
+    }
+
+    pub async fn panic(&mut self) -> Result<()> {
+        panic(&mut self.port).await?;
+        self.settle().await
+    }
+
+    // ^^p prints the user script stored in flash, or a note when there isn't one
+    pub async fn read_script(&mut self, timeouts: ReadTimeouts) -> Result<String> {
+        const NO_SCRIPT: &str = "no user script.";
+
+        self.write_all("^^p").await?;
+        let response = read_response(self, timeouts, |_| {}).await?;
+
+        match response.lines.as_slice() {
+            [] => Err(Error::NoScript),
+            [line] if line.trim() == NO_SCRIPT => Err(Error::NoScript),
+            lines => Ok(lines.join("\n")),
+        }
+    }
+
+    // Plain for short lines, fenced once they'd overflow crow's line buffer
+    pub async fn write_line(&mut self, line: &str) -> Result<()> {
+        write_line(&mut self.port, line, self.delimit_threshold).await?;
+        self.settle().await
+    }
+
+    // Send a command and wait briefly for the crow's reply, commands like assignments don't print
+    pub async fn write_and_read(&mut self, chunk: &str) -> Result<Option<String>> {
+        self.write_line(chunk).await?;
+
+        let response = self.read_reply().await?;
+        if self.strip_echo
+            && let Some(line) = &response
+            && line.trim_end() == chunk.trim_end()
+        {
+            info!(target: "pidgeon::serial", "Dropping echoed command");
+            return self.read_reply().await;
+        }
+
+        Ok(response)
+    }
+
+    // Like `write_and_read`, but follows the command with a marker print so its end is known: a
+    // command that prints nothing still gets acked, and only a missing marker means a timeout
+    pub async fn write_and_ack(&mut self, chunk: &str, timeout: Duration) -> Result<Acked> {
+        const MARKER: &str = "pidgeon-ack";
+        let ack = format!("print('{MARKER}')");
+
+        self.write_line(chunk).await?;
+        self.write_all(&ack).await?;
+
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut lines = Vec::new();
+        loop {
+            let line = match tokio::time::timeout_at(deadline, self.read_line()).await {
+                Ok(line) => line?,
+                Err(_) => {
+                    return Ok(Acked {
+                        lines,
+                        acked: false,
+                    });
+                }
+            };
+            if line.is_empty() {
+                return Err(Error::ConnectionClosed);
+            }
+
+            let line = line.trim_end();
+            if line == MARKER {
+                return Ok(Acked { lines, acked: true });
+            }
+            if self.strip_echo && (line == chunk.trim_end() || line == ack) {
+                continue;
+            }
+            lines.push(line.to_string());
+        }
+    }
+
+    // One line of reply, None if the crow stays quiet. Input events and other `^^` lines the crow
+    // sends by itself can land first, those are logged and skipped
+    pub async fn read_reply(&mut self) -> Result<Option<String>> {
+        let deadline = tokio::time::Instant::now() + self.response_timeout;
+
+        loop {
+            let line = match tokio::time::timeout_at(deadline, self.read_line()).await {
+                Ok(line) => line?,
+                Err(_) => return Ok(None),
+            };
+
+            if line.is_empty() || CrowEvent::parse(&line).is_response() {
+                return Ok(Some(line));
+            }
+            info!(target: "pidgeon::serial", "Skipping event while waiting for a reply: {line:?}");
+        }
+    }
+
+    // Send a ^^ query and wait for the `^^<event>(...)` line that answers it, None if it doesn't
+    // come in time. Lines along the way go to `on_line`
+    async fn query_event(
+        &mut self,
+        query: &str,
+        event: &str,
+        mut on_line: impl FnMut(&str),
+    ) -> Result<Option<String>> {
+        self.write_all(query).await?;
+        let deadline = tokio::time::Instant::now() + self.response_timeout;
+
+        loop {
+            let line = match tokio::time::timeout_at(deadline, self.read_line()).await {
+                Ok(line) => line?,
+                Err(_) => return Ok(None),
+            };
+
+            match CrowEvent::parse(&line) {
+                CrowEvent::Event { name, args } if name == event => {
+                    return Ok(Some(args.trim_matches(['\'', '"']).to_string()));
+                }
+                // Nothing more is coming
+                _ if line.is_empty() => return Ok(None),
+                _ => {
+                    info!(target: "pidgeon::serial", "Skipping while waiting for ^^{event}: {line:?}");
+                    on_line(line.trim_end());
+                }
+            }
+        }
+    }
+
+    // ^^v has the crow print its firmware version as `^^version('v4.0.4')`
+    pub async fn version(&mut self) -> Result<String> {
+        self.query_event("^^v", "version", |_| {})
+            .await?
+            .ok_or(Error::NoVersion(self.response_timeout))
+    }
+
+    // ^^i has the crow print its unique id as `^^identity('0x...')`
+    pub async fn identity(&mut self) -> Result<CrowIdentity> {
+        let mut calibrated = None;
+        let unique_id = self
+            .query_event("^^i", "identity", |line| {
+                calibrated = calibrated.or(calibration(line));
+            })
+            .await?
+            .ok_or_else(|| Error::Query {
+                query: "^^i".into(),
+                response: "no response".into(),
+            })?;
+
+        Ok(CrowIdentity {
+            unique_id,
+            calibrated,
+        })
+    }
+
+    pub async fn shutdown(&mut self, teardown: Teardown) -> Result<()> {
+        write_all(&mut self.port, teardown.command()).await?;
+        self.port.flush().await.map_err(Error::serial)
+    }
+
+    // Throw away anything the crow already printed, e.g. leftovers from the previous script, so
+    // the next read is a reply to whatever's sent next. Stops once the crow is quiet, or after
+    // DRAIN_LIMIT lines for a script that never stops printing
+    pub async fn drain(&mut self) -> Result<Drained> {
+        let mut drained = Drained::default();
+
+        while drained.lines < DRAIN_LIMIT {
+            match self.try_read_line().await? {
+                Some(line) if !line.is_empty() => drained.add(&line),
+                _ => break,
+            }
+        }
+        drained.bytes += std::mem::take(&mut self.partial).len();
+
+        drained.log();
+        Ok(drained)
+    }
+
+    // Have the crow print a token back, true if it arrived intact
+    pub async fn loopback(&mut self, token: &str) -> Result<bool> {
+        let response = self.write_and_read(&format!("print('{token}')")).await?;
+        Ok(response.is_some_and(|line| line.trim_end() == token))
+    }
+
+    // Lua heap in use, in KB
+    pub async fn memory_kb(&mut self) -> Result<f64> {
+        const QUERY: &str = "print(collectgarbage('count'))";
+
+        let response = self.write_and_read(QUERY).await?.unwrap_or_default();
+
+        response.trim().parse().map_err(|_| Error::Query {
+            query: QUERY.into(),
+            response: if response.trim().is_empty() {
+                "no response".into()
+            } else {
+                response.trim().into()
+            },
+        })
+    }
+
+    // A line exactly as the crow sent it, for output that might not be text
+    pub async fn read_raw(&mut self) -> Result<Vec<u8>> {
+        read_raw(&mut self.port, &mut self.partial).await
     }
 
     pub async fn read_line(&mut self) -> Result<String> {
-        read_line(&mut self.0).await
+        read_line(&mut self.port, &mut self.partial).await
+    }
+
+    // For a line that has to arrive, silence is an error like any other
+    pub async fn read_line_timeout(&mut self, timeout: Duration) -> Result<String> {
+        tokio::time::timeout(timeout, self.read_line())
+            .await
+            .map_err(|_| Error::Timeout(timeout))?
     }
 
     pub async fn try_read_line(&mut self) -> Result<Option<String>> {
-        read_line_if_available(&mut self.0).await
+        read_line_if_available(&mut self.port, &mut self.partial, self.poll_timeout).await
+    }
+}
+
+// What `drain` threw away
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Drained {
+    pub lines: usize,
+    pub bytes: usize,
+}
+
+impl Drained {
+    fn add(&mut self, line: &str) {
+        info!(target: "pidgeon::serial", "Discarding stale output: {line:?}");
+        self.lines += 1;
+        self.bytes += line.len();
+    }
+
+    fn log(&self) {
+        if self.bytes > 0 {
+            info!(target: "pidgeon::serial", "Drained {} line(s), {} byte(s)", self.lines, self.bytes);
+        }
     }
 }
 
-pub struct CrowWriter(WriteHalf<SerialStream>);
+const HALF_DUPLEX_SETTLE: Duration = Duration::from_millis(20);
+
+// Shared by a split reader and writer so the reader stands aside while a write goes out
+#[derive(Clone, Default)]
+struct HalfDuplex {
+    gate: Arc<Mutex<()>>,
+    want_write: Arc<Notify>,
+}
+
+pub struct CrowWriter {
+    port: WriteHalf<Port>,
+    half_duplex: Option<HalfDuplex>,
+    info: ConnectInfo,
+    delimit_threshold: usize,
+    // WriteHalf can't reach the control lines, so a cloned handle to the port is kept for them
+    control: Option<Box<dyn SerialPort>>,
+}
 
 impl CrowWriter {
+    // Reopen a crow that went away, this writer moves to the new port and the new port's reader is
+    // handed back, the old reader is dead and should be dropped
+    pub async fn reconnect(&mut self, policy: ReconnectPolicy) -> Result<CrowReader> {
+        let mut crow = Crow::reopen(&self.info, policy).await?;
+        crow.set_half_duplex(self.half_duplex.is_some());
+        crow.set_delimit_threshold(self.delimit_threshold);
+
+        let (reader, writer) = crow.split();
+        *self = writer;
+
+        Ok(reader)
+    }
+
+    // Interrupt the reader and hold it off until the guard drops. The reader can wake and re-take
+    // the gate before this task queues for it, so keep nudging until it's ours
+    async fn pause_reader(&self) -> Option<OwnedMutexGuard<()>> {
+        let half_duplex = self.half_duplex.as_ref()?;
+
+        loop {
+            half_duplex.want_write.notify_one();
+
+            let lock = half_duplex.gate.clone().lock_owned();
+            if let Ok(guard) = tokio::time::timeout(Duration::from_millis(5), lock).await {
+                break Some(guard);
+            }
+        }
+    }
+
+    async fn settle(&mut self) -> Result<()> {
+        if self.half_duplex.is_some() {
+            self.port.flush().await.map_err(Error::serial)?;
+            tokio::time::sleep(HALF_DUPLEX_SETTLE).await;
+        }
+
+        Ok(())
+    }
+
     pub async fn write_delimited(&mut self, chunk: &str) -> Result<()> {
-        write_delimited(&mut self.0, chunk.as_bytes()).await
+        let _paused = self.pause_reader().await;
+        write_delimited(&mut self.port, chunk.as_bytes()).await?;
+        self.settle().await
     }
 
     pub async fn write_script(&mut self, chunk: &str) -> Result<()> {
-        write_script(&mut self.0, chunk.as_bytes()).await
+        let _paused = self.pause_reader().await;
+        write_script(&mut self.port, chunk.as_bytes()).await?;
+        self.settle().await
+    }
+
+    pub async fn write_flash_script(&mut self, chunk: &str) -> Result<()> {
+        let _paused = self.pause_reader().await;
+        write_flash_script(&mut self.port, chunk.as_bytes()).await?;
+        self.settle().await
+    }
+
+    pub async fn upload(&mut self, chunk: &str, opts: UploadOptions) -> Result<()> {
+        let _paused = self.pause_reader().await;
+        upload(&mut self.port, chunk.as_bytes(), opts).await?;
+        self.settle().await
+    }
+
+    pub async fn panic(&mut self) -> Result<()> {
+        let _paused = self.pause_reader().await;
+        panic(&mut self.port).await?;
+        self.settle().await
+    }
+
+    // ^^c clears the user script, crow falls back to its default blank script
+    pub async fn clear_script(&mut self) -> Result<()> {
+        let _paused = self.pause_reader().await;
+        write_all(&mut self.port, b"^^c").await?;
+        self.settle().await
+    }
+
+    // ^^f runs First, the script built into crow's firmware. It's loaded into RAM only, the user
+    // script in flash stays as it is and runs again on the next boot
+    pub async fn run_first(&mut self) -> Result<()> {
+        let _paused = self.pause_reader().await;
+        write_all(&mut self.port, b"^^f").await?;
+        self.settle().await
+    }
+
+    // ^^r runs the script stored in flash, without re-uploading it. Whatever's in RAM is thrown
+    // away first, what the script prints comes through the reader
+    pub async fn run_flash_script(&mut self) -> Result<()> {
+        let _paused = self.pause_reader().await;
+        write_all(&mut self.port, b"^^r").await?;
+        self.settle().await
+    }
+
+    // Crow has no restart that keeps what's in RAM, it restarts by running the flash script
+    // again, so this is the same ^^r. Crow prints its boot banner as it comes back up
+    pub async fn restart(&mut self) -> Result<()> {
+        self.run_flash_script().await
+    }
+
+    pub fn set_delimit_threshold(&mut self, threshold: usize) {
+        self.delimit_threshold = threshold;
+    }
+
+    pub fn set_dtr(&mut self, level: bool) -> Result<()> {
+        let control = self.control.as_mut().ok_or(Error::NoControlLines)?;
+        Ok(control.write_data_terminal_ready(level)?)
+    }
+
+    pub fn set_rts(&mut self, level: bool) -> Result<()> {
+        let control = self.control.as_mut().ok_or(Error::NoControlLines)?;
+        Ok(control.write_request_to_send(level)?)
+    }
+
+    pub async fn shutdown(&mut self, teardown: Teardown) -> Result<()> {
+        let _paused = self.pause_reader().await;
+        write_all(&mut self.port, teardown.command()).await?;
+        self.port.flush().await.map_err(Error::serial)
+    }
+
+    // Plain for short lines, fenced once they'd overflow crow's line buffer
+    pub async fn write_line(&mut self, line: &str) -> Result<()> {
+        let _paused = self.pause_reader().await;
+        write_line(&mut self.port, line, self.delimit_threshold).await?;
+        self.settle().await
+    }
+
+    // A group of lines sent as one write, each framed like `write_line`. A failure is reported
+    // against the first line that didn't make it out whole
+    pub async fn write_lines(&mut self, lines: &[&str]) -> Result<()> {
+        let (buf, ends) = frame_lines(lines, self.delimit_threshold);
+        info!(target: "pidgeon::serial", "Writing {} lines in {} bytes", lines.len(), buf.len());
+
+        let _paused = self.pause_reader().await;
+        let mut written = 0;
+        while written < buf.len() {
+            match self.port.write(&buf[written..]).await {
+                Ok(0) => {
+                    return Err(line_error(
+                        &ends,
+                        written,
+                        std::io::ErrorKind::WriteZero.into(),
+                    ));
+                }
+                Ok(n) => written += n,
+                Err(err) => return Err(line_error(&ends, written, err)),
+            }
+        }
+        self.settle().await
     }
 
     pub async fn write_all(&mut self, chunk: &str) -> Result<()> {
-        write_all(&mut self.0, chunk.as_bytes()).await
+        let _paused = self.pause_reader().await;
+        write_all(&mut self.port, chunk.as_bytes()).await?;
+        self.settle().await
+    }
+}
+
+impl LineSource for Crow {
+    async fn next_line(&mut self) -> Result<String> {
+        self.read_line().await
+    }
+}
+
+impl LineSource for CrowReader {
+    async fn next_line(&mut self) -> Result<String> {
+        self.read_once().await
+    }
+}
+
+const FLUSH_INTERVAL: Duration = Duration::from_millis(50);
+
+// LinesCodec, except a line that isn't valid UTF-8 comes back as an error item rather than
+// failing the stream, which FramedRead ends after any decoder error
+#[derive(Debug, Default)]
+pub struct CrowCodec(LinesCodec);
+
+type Decoded = std::result::Result<String, LinesCodecError>;
+
+impl CrowCodec {
+    fn recover(
+        res: std::result::Result<Option<String>, LinesCodecError>,
+    ) -> std::result::Result<Option<Decoded>, LinesCodecError> {
+        match res {
+            Ok(line) => Ok(line.map(Ok)),
+            // The bad line has already been consumed from the buffer, so decoding can carry on
+            Err(LinesCodecError::Io(err)) if err.kind() == std::io::ErrorKind::InvalidData => {
+                Ok(Some(Err(LinesCodecError::Io(err))))
+            }
+            Err(err) => Err(err),
+        }
     }
 }
 
-pub struct CrowReader(FramedRead<ReadHalf<SerialStream>, LinesCodec>);
+impl Decoder for CrowCodec {
+    type Item = Decoded;
+    type Error = LinesCodecError;
+
+    fn decode(
+        &mut self,
+        buf: &mut BytesMut,
+    ) -> std::result::Result<Option<Decoded>, LinesCodecError> {
+        Self::recover(self.0.decode(buf))
+    }
+
+    fn decode_eof(
+        &mut self,
+        buf: &mut BytesMut,
+    ) -> std::result::Result<Option<Decoded>, LinesCodecError> {
+        Self::recover(self.0.decode_eof(buf))
+    }
+}
+
+pub struct CrowReader(
+    FramedRead<ReadHalf<Port>, CrowCodec>,
+    Option<HalfDuplex>,
+    // Kept so a reconnect finds the same crow again
+    ConnectInfo,
+);
 
 impl CrowReader {
-    pub async fn run(mut self) {
-        while let Some(reply) = self.0.next().await {
+    // Reads in half duplex mode are abandoned when a write wants the line, then resumed after.
+    // FramedRead keeps partial lines buffered so nothing is lost
+    async fn next(&mut self) -> Option<Decoded> {
+        let Some(half_duplex) = self.1.clone() else {
+            return self.0.next().await.map(|res| res.and_then(|line| line));
+        };
+
+        loop {
+            let _gate = half_duplex.gate.lock().await;
+            tokio::select! {
+                line = self.0.next() => return line.map(|res| res.and_then(|line| line)),
+                _ = half_duplex.want_write.notified() => {}
+            }
+        }
+    }
+
+    // Every line the crow prints, until it goes away. A line that fails to decode is an error
+    // item, the stream carries on after it
+    pub fn stream(self) -> impl Stream<Item = Result<String>> {
+        futures::stream::unfold(self, |mut reader| async {
+            let line = reader.next().await?;
+            Some((line.map_err(Error::serial_codec), reader))
+        })
+    }
+
+    // Print everything the crow says. A garbled line is skipped, but the stream ending means the
+    // crow went away, so reconnect if a policy is given
+    pub async fn run(self, reconnect: Option<ReconnectPolicy>) {
+        self.forward(reconnect, |line| {
+            println!("{line}");
+            true
+        })
+        .await
+    }
+
+    // Like `run`, but each line goes to `tx` for the caller to show however it likes. Stops once
+    // the receiving end is dropped
+    pub async fn run_to(self, tx: UnboundedSender<String>, reconnect: Option<ReconnectPolicy>) {
+        self.forward(reconnect, |line| tx.send(line).is_ok()).await
+    }
+
+    async fn forward(
+        self,
+        reconnect: Option<ReconnectPolicy>,
+        mut emit: impl FnMut(String) -> bool,
+    ) {
+        let mut reader = self;
+        loop {
+            let info = reader.2.clone();
+
+            let mut lines = std::pin::pin!(reader.stream());
+            while let Some(reply) = lines.next().await {
+                let line = match reply {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        error!(target: "pidgeon::serial", "Read error: {e:?}");
+                        "Crow couldn't find the words".into()
+                    }
+                };
+                if !emit(line) {
+                    return;
+                }
+            }
+
+            let Some(policy) = reconnect else {
+                break;
+            };
+
+            emit("Lost the crow, reconnecting".into());
+            match Crow::reopen(&info, policy).await {
+                Ok(crow) => {
+                    emit(format!("Reconnected to {}", crow.info().path));
+                    reader = crow.split().0;
+                }
+                Err(err) => {
+                    emit(err.to_string());
+                    break;
+                }
+            }
+        }
+    }
+
+    // Like `run`, but coalesces output so a script printing rapidly isn't bottlenecked on stdout.
+    // Lines are flushed every `batch` lines, or after a short quiet period so nothing lingers
+    pub async fn run_buffered(mut self, batch: usize) -> std::io::Result<()> {
+        use std::io::Write;
+
+        let mut out = std::io::BufWriter::with_capacity(64 * 1024, std::io::stdout());
+        let mut pending = 0;
+
+        loop {
+            let reply = if pending == 0 {
+                self.next().await
+            } else {
+                match tokio::time::timeout(FLUSH_INTERVAL, self.next()).await {
+                    Ok(reply) => reply,
+                    Err(_) => {
+                        out.flush()?;
+                        pending = 0;
+                        continue;
+                    }
+                }
+            };
+
             match reply {
-                Ok(msg) => println!("{msg}"),
-                Err(e) => {
-                    println!("Crow couldn't find the words");
-                    error!("Read error: {e:?}");
+                Some(Ok(msg)) => {
+                    writeln!(out, "{msg}")?;
+                    pending += 1;
+                }
+                Some(Err(e)) => {
+                    writeln!(out, "Crow couldn't find the words")?;
+                    pending += 1;
+                    error!(target: "pidgeon::serial", "Read error: {e:?}");
                 }
+                None => break,
+            }
+
+            if pending >= batch {
+                out.flush()?;
+                pending = 0;
             }
         }
+
+        out.flush()
     }
 
     pub async fn read_once(&mut self) -> Result<String> {
-        Ok(self.0.next().await.ok_or(Error::ConnectionClosed)??)
+        self.next()
+            .await
+            .ok_or(Error::ConnectionClosed)?
+            .map_err(Error::serial_codec)
+    }
+
+    pub async fn drain(&mut self) -> Result<Drained> {
+        let mut drained = Drained::default();
+
+        while drained.lines < DRAIN_LIMIT {
+            match self.read_within(Duration::from_millis(10)).await? {
+                Some(line) => drained.add(&line),
+                None => break,
+            }
+        }
+
+        drained.log();
+        Ok(drained)
+    }
+
+    // None if the crow stays quiet for `timeout`
+    pub async fn read_within(&mut self, timeout: Duration) -> Result<Option<String>> {
+        match tokio::time::timeout(timeout, self.read_once()).await {
+            Ok(line) => line.map(Some),
+            Err(_) => Ok(None),
+        }
     }
 }
 
 // General read/write ops w/ crow's protocol
 
+// Lines framed for crow back to back, fenced past `threshold` bytes, with where each one ends
+pub fn frame_lines(lines: &[&str], threshold: usize) -> (Vec<u8>, Vec<usize>) {
+    let mut buf = Vec::new();
+    let mut ends = Vec::with_capacity(lines.len());
+
+    for line in lines {
+        if line.len() > threshold {
+            buf.extend_from_slice(b"```");
+            buf.extend_from_slice(line.as_bytes());
+            buf.extend_from_slice(b"```\n");
+        } else {
+            buf.extend_from_slice(line.as_bytes());
+            buf.push(b'\n');
+        }
+        ends.push(buf.len());
+    }
+
+    (buf, ends)
+}
+
+// A single line framed by `frame_lines`, the one place that decides what gets fenced
+pub async fn write_line<W>(writer: &mut W, line: &str, threshold: usize) -> Result<()>
+where
+    W: AsyncWriteExt + Unpin,
+{
+    let (buf, _) = frame_lines(&[line], threshold);
+    info!(target: "pidgeon::serial", "Writing line: {:?}", String::from_utf8_lossy(&buf));
+
+    writer.write_all(&buf).await.map_err(Error::serial)
+}
+
+fn line_error(ends: &[usize], written: usize, err: std::io::Error) -> Error {
+    Error::WriteLine {
+        index: ends.partition_point(|&end| end <= written),
+        source: Box::new(Error::serial(err)),
+    }
+}
+
 pub async fn write_all<W>(writer: &mut W, chunk: &[u8]) -> Result<()>
 where
     W: AsyncWriteExt + Unpin,
 {
-    info!("Writing bytes: {:?}", String::from_utf8_lossy(chunk));
+    info!(target: "pidgeon::serial", "Writing bytes: {:?}", String::from_utf8_lossy(chunk));
+
+    writer.write_all(chunk).await.map_err(Error::serial)?;
+    writer.write_all(b"\n").await.map_err(Error::serial)?;
+    Ok(())
+}
+
+// Everything stops, as fast as possible: ^^c clears the user script, then all four outputs are set
+// to 0V. Sent as one write and flushed, nothing waits on the crow to answer
+const PANIC: &[u8] = b"^^c\n\
+output[1].volts = 0\n\
+output[2].volts = 0\n\
+output[3].volts = 0\n\
+output[4].volts = 0\n";
+
+pub async fn panic<W>(writer: &mut W) -> Result<()>
+where
+    W: AsyncWriteExt + Unpin,
+{
+    writer.write_all(PANIC).await.map_err(Error::serial)?;
+    writer.flush().await.map_err(Error::serial)?;
 
-    writer.write_all(chunk).await?;
-    writer.write_all(b"\n").await?;
     Ok(())
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct UploadOptions {
+    // End with ^^w instead of ^^e, writing the script to flash so it runs on boot
+    pub flash: bool,
+    // Some firmware evaluates the newline after the end marker as an empty command
+    pub trailing_newline: bool,
+    // Larger scripts go out in pieces this big, with a pause between them so a slow USB stack
+    // doesn't overrun crow's input buffer
+    pub chunk_size: usize,
+    pub chunk_delay: Duration,
+}
+
+impl Default for UploadOptions {
+    fn default() -> Self {
+        Self {
+            flash: false,
+            trailing_newline: true,
+            chunk_size: 256,
+            chunk_delay: Duration::from_millis(5),
+        }
+    }
+}
+
+// ^^s starts an upload, ^^e ends it and runs the script from RAM
 pub async fn write_script<W>(writer: &mut W, script: &[u8]) -> Result<()>
 where
     W: AsyncWriteExt + Unpin,
 {
-    info!("Writing script: {:?}", &script[..256]);
+    upload(writer, script, UploadOptions::default()).await
+}
 
-    writer.write_all(b"^^s").await?;
-    writer.write_all(script).await?;
-    writer.write_all(b"^^e").await?;
-    writer.write_all(b"\n").await?;
+// ^^w ends an upload like ^^e, but also writes the script to flash so it runs on boot
+pub async fn write_flash_script<W>(writer: &mut W, script: &[u8]) -> Result<()>
+where
+    W: AsyncWriteExt + Unpin,
+{
+    let opts = UploadOptions {
+        flash: true,
+        ..Default::default()
+    };
+
+    upload(writer, script, opts).await
+}
+
+pub async fn upload<W>(writer: &mut W, script: &[u8], opts: UploadOptions) -> Result<()>
+where
+    W: AsyncWriteExt + Unpin,
+{
+    info!(target: "pidgeon::serial",
+        "Writing script: {:?}",
+        String::from_utf8_lossy(&script[..script.len().min(256)])
+    );
+
+    writer.write_all(b"^^s").await.map_err(Error::serial)?;
+    if script.len() <= opts.chunk_size {
+        writer.write_all(script).await.map_err(Error::serial)?;
+    } else {
+        for chunk in script.chunks(opts.chunk_size.max(1)) {
+            writer.write_all(chunk).await.map_err(Error::serial)?;
+            writer.flush().await.map_err(Error::serial)?;
+            tokio::time::sleep(opts.chunk_delay).await;
+        }
+    }
+    writer
+        .write_all(if opts.flash { b"^^w" } else { b"^^e" })
+        .await
+        .map_err(Error::serial)?;
+    if opts.trailing_newline {
+        writer.write_all(b"\n").await.map_err(Error::serial)?;
+    }
 
     Ok(())
 }
@@ -139,47 +1506,226 @@ pub async fn write_delimited<W>(writer: &mut W, chunk: &[u8]) -> Result<()>
 where
     W: AsyncWriteExt + Unpin,
 {
-    info!("Writing chunk of text w/ len > 64b");
+    info!(target: "pidgeon::serial", "Writing fenced chunk of {} bytes", chunk.len());
 
-    writer.write_all(b"```").await?;
-    writer.write_all(chunk).await?;
-    writer.write_all(b"```").await?;
-    writer.write_all(b"\n").await?;
+    writer.write_all(b"```").await.map_err(Error::serial)?;
+    writer.write_all(chunk).await.map_err(Error::serial)?;
+    writer.write_all(b"```").await.map_err(Error::serial)?;
+    writer.write_all(b"\n").await.map_err(Error::serial)?;
 
     Ok(())
 }
 
-pub async fn read_line<R>(reader: &mut R) -> Result<String>
+// Bytes are appended to `partial` as they arrive, so a read cut short by a timeout carries on
+// where it left off next time instead of dropping half a line. Returned as-is, newline included,
+// whatever the crow sent
+pub async fn read_raw<R>(reader: &mut R, partial: &mut Vec<u8>) -> Result<Vec<u8>>
 where
-    R: AsyncRead + Unpin,
+    R: AsyncBufRead + Unpin,
 {
     // I never get to write any fun low-level bullshit bc tokio already has it >:(
-    let mut bufreader = BufReader::new(reader);
+    reader
+        .read_until(b'\n', partial)
+        .await
+        .map_err(Error::serial)?;
 
-    let mut buf = String::with_capacity(512);
-    bufreader.read_line(&mut buf).await?;
+    Ok(std::mem::take(partial))
+}
 
-    // shrink her
-    buf.shrink_to_fit();
+pub async fn read_line<R>(reader: &mut R, partial: &mut Vec<u8>) -> Result<String>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = read_raw(reader, partial).await?;
 
-    Ok(buf)
+    // Some firmware ends lines with \r\n, some with \n. Both come back ending in a bare \n, the
+    // split reader's LinesCodec drops the \r the same way
+    if line.ends_with(b"\r\n") {
+        line.remove(line.len() - 2);
+    }
+
+    String::from_utf8(line)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err).into())
 }
 
-pub async fn read_line_if_available<R>(reader: &mut R) -> Result<Option<String>>
+pub async fn read_line_if_available<R>(
+    reader: &mut R,
+    partial: &mut Vec<u8>,
+    timeout: Duration,
+) -> Result<Option<String>>
 where
-    R: AsyncRead + Unpin,
+    R: AsyncBufRead + Unpin,
 {
-    let mut bufreader = BufReader::new(reader);
+    match tokio::time::timeout(timeout, read_line(reader, partial)).await {
+        Ok(line) => line.map(Some),
+        Err(_) => Ok(None),
+    }
+}
 
-    let mut buf = String::with_capacity(512);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    if let Ok(read) =
-        tokio::time::timeout(Duration::from_millis(10), bufreader.read_line(&mut buf)).await
-    {
-        let _ = read?;
-        buf.shrink_to_fit();
-        Ok(Some(buf))
-    } else {
-        Ok(None)
+    fn framed(line: &str) -> Vec<u8> {
+        frame_lines(&[line], LINE_LIMIT).0
+    }
+
+    #[test]
+    fn short_lines_go_out_plain() {
+        for len in [LINE_LIMIT - 1, LINE_LIMIT] {
+            let line = "x".repeat(len);
+            assert_eq!(
+                framed(&line),
+                format!("{line}\n").into_bytes(),
+                "{len} bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn long_lines_are_fenced() {
+        let line = "x".repeat(LINE_LIMIT + 1);
+        assert_eq!(framed(&line), format!("```{line}```\n").into_bytes());
+    }
+
+    // Counted in bytes, a line of multibyte characters is fenced well before 64 of them
+    #[test]
+    fn threshold_counts_bytes() {
+        let line = "é".repeat(LINE_LIMIT / 2 + 1);
+        assert!(line.chars().count() < LINE_LIMIT);
+        assert_eq!(framed(&line), format!("```{line}```\n").into_bytes());
+    }
+
+    const QUICK: ReconnectPolicy = ReconnectPolicy {
+        max_attempts: 4,
+        backoff: Duration::from_millis(1),
+        max_backoff: Duration::from_millis(2),
+    };
+
+    // Stands in for a crow that turns up after `failures` tries
+    fn flaky(failures: u32) -> impl FnMut() -> std::future::Ready<Result<u32>> {
+        let mut attempts = 0;
+        move || {
+            attempts += 1;
+            std::future::ready(if attempts > failures {
+                Ok(attempts)
+            } else {
+                Err(Error::NotFound(Some(format!("attempt {attempts}"))))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_until_connected() {
+        assert_eq!(retry(QUICK, flaky(0)).await.unwrap(), 1);
+        assert_eq!(retry(QUICK, flaky(3)).await.unwrap(), 4);
+    }
+
+    #[tokio::test]
+    async fn retry_gives_up_with_the_last_error() {
+        match retry(QUICK, flaky(4)).await {
+            Err(Error::ReconnectFailed { attempts: 4, last }) => {
+                assert!(matches!(*last, Error::NotFound(Some(ref why)) if why == "attempt 4"))
+            }
+            res => panic!("expected to give up, got {res:?}"),
+        }
+    }
+
+    // A script big enough to go out in chunks arrives whole, between one ^^s and one ^^e
+    #[tokio::test]
+    async fn chunked_upload_reassembles() {
+        let script = (0..4096)
+            .map(|i| b"abcdefghijklmnopqrstuvwxyz\n"[i % 27])
+            .collect::<Vec<_>>();
+        assert!(script.len() > UploadOptions::default().chunk_size);
+
+        let mut written = Vec::new();
+        write_script(&mut written, &script).await.unwrap();
+
+        let mut want = b"^^s".to_vec();
+        want.extend_from_slice(&script);
+        want.extend_from_slice(b"^^e\n");
+        assert_eq!(written, want);
+    }
+
+    // Short lines plain and long ones fenced, all in the one buffer that goes to the port
+    #[tokio::test]
+    async fn write_lines_mixes_framing() {
+        use tokio::io::AsyncReadExt;
+
+        let long = "x".repeat(LINE_LIMIT + 1);
+        let want = format!("a = 1\n```{long}```\nprint(a)\n");
+
+        let (leader, mut follower) = SerialStream::pair().unwrap();
+        let (_reader, mut writer) = Crow::mock(leader).split();
+        writer
+            .write_lines(&["a = 1", &long, "print(a)"])
+            .await
+            .unwrap();
+
+        let mut written = vec![0; want.len()];
+        follower.read_exact(&mut written).await.unwrap();
+        assert_eq!(String::from_utf8(written).unwrap(), want);
+    }
+
+    #[test]
+    fn write_error_names_the_line() {
+        let long = "x".repeat(LINE_LIMIT + 1);
+        let (_, ends) = frame_lines(&["a = 1", &long, "print(a)"], LINE_LIMIT);
+        assert_eq!(
+            ends,
+            [6, 6 + LINE_LIMIT + 1 + 7, 6 + LINE_LIMIT + 1 + 7 + 9]
+        );
+
+        let index = |written| match line_error(&ends, written, std::io::ErrorKind::WriteZero.into())
+        {
+            Error::WriteLine { index, .. } => index,
+            err => panic!("expected a WriteLine, got {err:?}"),
+        };
+        assert_eq!(index(0), 0);
+        assert_eq!(index(5), 0);
+        assert_eq!(index(6), 1);
+        assert_eq!(index(ends[1]), 2);
+    }
+
+    // Both lines arrive in one read, the second has to still be buffered for the next call
+    #[tokio::test]
+    async fn lines_in_one_burst_are_both_read() {
+        let (leader, mut follower) = SerialStream::pair().unwrap();
+        let mut crow = Crow::mock(leader);
+
+        follower.write_all(b"one\ntwo\n").await.unwrap();
+        assert_eq!(crow.read_line().await.unwrap(), "one\n");
+        assert_eq!(
+            crow.try_read_line().await.unwrap().as_deref(),
+            Some("two\n")
+        );
+    }
+
+    // Some firmware ends lines with \r\n, and the \r can come in a read of its own
+    #[tokio::test]
+    async fn crlf_is_stripped() {
+        let (leader, mut follower) = SerialStream::pair().unwrap();
+        let mut crow = Crow::mock(leader);
+
+        follower.write_all(b"foo\r").await.unwrap();
+        assert_eq!(crow.try_read_line().await.unwrap(), None);
+        follower.write_all(b"\n").await.unwrap();
+        assert_eq!(crow.read_line().await.unwrap(), "foo\n");
+
+        // The split reader's LinesCodec drops it the same way
+        follower.write_all(b"bar\r\n").await.unwrap();
+        let (mut reader, _writer) = crow.split();
+        assert_eq!(reader.read_once().await.unwrap(), "bar");
+    }
+
+    #[tokio::test]
+    async fn write_line_matches_frame_lines() {
+        for len in [LINE_LIMIT - 1, LINE_LIMIT, LINE_LIMIT + 1] {
+            let line = "x".repeat(len);
+            let mut written = Vec::new();
+            write_line(&mut written, &line, LINE_LIMIT).await.unwrap();
+            assert_eq!(written, framed(&line), "{len} bytes");
+        }
     }
 }
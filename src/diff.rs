@@ -0,0 +1,58 @@
+use super::{
+    crow::{Crow, ReadTimeouts},
+    error::*,
+};
+use similar::{ChangeTag, TextDiff};
+use std::path::Path;
+
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const RESET: &str = "\x1b[0m";
+
+// Crow may send the script back with different line endings and trailing whitespace, none of
+// which changes what runs
+fn normalize(script: &str) -> String {
+    let mut lines = script.lines().map(str::trim_end).collect::<Vec<_>>();
+    while lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+
+    lines.into_iter().map(|line| format!("{line}\n")).collect()
+}
+
+// Print a line diff of the crow's stored script against `path`, erroring if they differ
+pub async fn run(crow: &mut Crow, path: &Path, timeouts: ReadTimeouts, color: bool) -> Result<()> {
+    let local =
+        String::from_utf8(std::fs::read(path)?).map_err(|_| Error::NotUtf8(path.to_path_buf()))?;
+    let stored = crow.read_script(timeouts).await?;
+
+    let (stored, local) = (normalize(&stored), normalize(&local));
+    if stored == local {
+        println!("crow's script matches {}", path.display());
+        return Ok(());
+    }
+
+    println!("--- crow");
+    println!("+++ {}", path.display());
+
+    let diff = TextDiff::from_lines(&stored, &local);
+    for hunk in diff.unified_diff().context_radius(3).iter_hunks() {
+        println!("{}", hunk.header());
+        for change in hunk.iter_changes() {
+            let (sign, style) = match change.tag() {
+                ChangeTag::Delete => ("-", RED),
+                ChangeTag::Insert => ("+", GREEN),
+                ChangeTag::Equal => (" ", ""),
+            };
+
+            let line = change.value().trim_end();
+            if color && !style.is_empty() {
+                println!("{style}{sign}{line}{RESET}");
+            } else {
+                println!("{sign}{line}");
+            }
+        }
+    }
+
+    Err(Error::ScriptsDiffer(path.to_path_buf()))
+}
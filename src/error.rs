@@ -1,22 +1,119 @@
 use rustyline::error::ReadlineError;
+use std::path::PathBuf;
+use std::time::Duration;
 use tokio_util::codec::LinesCodecError;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Serial Error: {0}")]
     Serial(#[from] tokio_serial::Error),
-    #[error("Crow not found")]
-    NotFound,
+    // What was asked for, when it was a particular crow
+    #[error("Crow not found{}", .0.as_ref().map(|wanted| format!(" with {wanted}")).unwrap_or_default())]
+    NotFound(Option<String>),
     #[error("IO error '{0}'")]
     IO(#[from] std::io::Error),
     #[error("Repl error '{0}'")]
     Repl(#[from] ReadlineError),
+    #[error(
+        "The REPL needs an interactive terminal ({0}), use `pidgeon exec` or `pidgeon file` instead"
+    )]
+    NoTerminal(ReadlineError),
     #[error("Unexpected response from crow: '{0}'")]
     Codec(#[from] LinesCodecError),
+    #[error("Found more than one crow ({0}), pick one with --serial, --index or --port")]
+    AmbiguousCrow(String),
+    #[error("{0} isn't a standard baud rate")]
+    InvalidBaud(u32),
+    #[error("Crow not found, port enumeration timed out after {0:?}")]
+    DetectTimeout(Duration),
+    #[error("Crow not found after {attempts} reconnect attempts: {last}")]
+    ReconnectFailed { attempts: u32, last: Box<Error> },
+    #[error("Can't reach this port's DTR/RTS lines")]
+    NoControlLines,
+    #[error("Crow disconnected: {0}")]
+    Disconnected(std::io::Error),
+    #[error("Writing line {index} failed: {source}")]
+    WriteLine { index: usize, source: Box<Error> },
     #[error("Connection closed")]
     ConnectionClosed,
+    #[error("Server didn't start a session: {0}")]
+    SessionRefused(String),
+    #[error("TLS setup failed: {0}")]
+    Tls(String),
+    #[error("Client didn't answer a heartbeat within {0:?}")]
+    HeartbeatTimeout(Duration),
+    #[error("Message of {len} bytes is over the {max} byte limit")]
+    MessageTooLarge { len: usize, max: usize },
     #[error("Serialization failed: '{0}'")]
     Serialization(#[from] serde_json::Error),
+    #[error("Crow couldn't answer '{query}': {response}")]
+    Query { query: String, response: String },
+    #[error("Script failed to compile: {message}")]
+    ScriptCompile { message: String },
+    #[error("Crow didn't say anything within {0:?}")]
+    Timeout(Duration),
+    #[error("Crow didn't report its firmware version within {0:?}")]
+    NoVersion(Duration),
+    #[error("Crow has no stored script")]
+    NoScript,
+    #[error("Crow's script differs from '{}'", .0.display())]
+    ScriptsDiffer(PathBuf),
+    #[error("Script '{}' is not valid UTF-8", .0.display())]
+    NotUtf8(PathBuf),
+    #[error("{0}, pass --yes to go ahead")]
+    NeedsConfirmation(&'static str),
+    #[error("IO test failed for {0}")]
+    IoTestFailed(String),
+    #[error("Self-test failed at {0}")]
+    SelfTest(String),
+    #[error("Invalid config: '{0}'")]
+    Config(#[from] toml::de::Error),
+    #[error("Unknown profile '{name}', available: {available}")]
+    UnknownProfile { name: String, available: String },
+    #[error("Unknown macro '{alias}', available: {available}")]
+    UnknownMacro { alias: String, available: String },
+}
+
+fn gone(err: &std::io::Error) -> bool {
+    use std::io::ErrorKind::*;
+
+    // EIO, ENXIO and ENODEV are what a USB serial device that vanished reports
+    matches!(err.kind(), BrokenPipe | NotConnected | UnexpectedEof)
+        || matches!(err.raw_os_error(), Some(5 | 6 | 19))
+}
+
+impl Error {
+    // For IO on the serial port, where an error that means the device is gone gets its own variant
+    pub(crate) fn serial(err: std::io::Error) -> Self {
+        if gone(&err) {
+            Error::Disconnected(err)
+        } else {
+            Error::IO(err)
+        }
+    }
+
+    pub(crate) fn serial_codec(err: LinesCodecError) -> Self {
+        match err {
+            LinesCodecError::Io(err) if gone(&err) => Error::Disconnected(err),
+            err => Error::Codec(err),
+        }
+    }
+
+    // The crow went away, e.g. it was unplugged, as opposed to a problem with what was sent
+    pub fn is_disconnect(&self) -> bool {
+        match self {
+            Error::Disconnected(_) | Error::ConnectionClosed => true,
+            Error::WriteLine { source, .. } => source.is_disconnect(),
+            Error::IO(err) => gone(err),
+            Error::Codec(LinesCodecError::Io(err)) => gone(err),
+            Error::Serial(err) => match err.kind() {
+                tokio_serial::ErrorKind::NoDevice => true,
+                tokio_serial::ErrorKind::Io(kind) => gone(&kind.into()),
+                _ => false,
+            },
+            _ => false,
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
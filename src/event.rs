@@ -0,0 +1,144 @@
+use super::crow::is_compile_error;
+use regex::Regex;
+use serde::Serialize;
+use std::sync::LazyLock;
+
+// Errors from running Lua, e.g. `[string "eval"]:1: attempt to call a nil value (global 'foo')`
+static RUNTIME_ERROR: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"^(lua: )?\[string "[^"]*"\]:\d+: "#).expect("valid regex"));
+
+// Crow reports input events as `^^<event>(<args>)` lines, e.g. `^^stream(1,0.25)` from an input in
+// stream mode or `^^change(2,1)` from one in change mode. Other `^^` lines, like `^^pub(...)`, are
+// kept as a name and raw args. Everything else is printed by a script or is a reply to a command
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", content = "data", rename_all = "lowercase")]
+pub enum CrowEvent {
+    Stream { channel: u8, volts: f64 },
+    Change { channel: u8, state: bool },
+    Event { name: String, args: String },
+    Error(String),
+    Line(String),
+}
+
+impl CrowEvent {
+    pub fn parse(line: &str) -> Self {
+        let line = line.trim_end();
+
+        let Some((name, args)) = line
+            .strip_prefix("^^")
+            .and_then(|event| event.strip_suffix(')'))
+            .and_then(|event| event.split_once('('))
+        else {
+            return if is_compile_error(line) || RUNTIME_ERROR.is_match(line) {
+                CrowEvent::Error(line.to_string())
+            } else {
+                CrowEvent::Line(line.to_string())
+            };
+        };
+
+        let input = || {
+            let (channel, value) = args.split_once(',')?;
+            Some((channel.trim().parse().ok()?, value.trim()))
+        };
+
+        let parsed = match name {
+            "stream" => input().and_then(|(channel, value)| {
+                Some(CrowEvent::Stream {
+                    channel,
+                    volts: value.parse().ok()?,
+                })
+            }),
+            "change" => input().and_then(|(channel, value)| {
+                Some(CrowEvent::Change {
+                    channel,
+                    state: value.parse::<f64>().ok()? != 0.0,
+                })
+            }),
+            _ => None,
+        };
+
+        parsed.unwrap_or_else(|| CrowEvent::Event {
+            name: name.to_string(),
+            args: args.to_string(),
+        })
+    }
+
+    // Lines that answer whatever was just sent, rather than something the crow reports by itself
+    pub fn is_response(&self) -> bool {
+        matches!(self, CrowEvent::Line(_) | CrowEvent::Error(_))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_events() {
+        assert_eq!(
+            CrowEvent::parse("^^stream(1,0.25)\r\n"),
+            CrowEvent::Stream {
+                channel: 1,
+                volts: 0.25
+            }
+        );
+        assert_eq!(
+            CrowEvent::parse("^^change(2,1)"),
+            CrowEvent::Change {
+                channel: 2,
+                state: true
+            }
+        );
+        assert_eq!(
+            CrowEvent::parse("^^change(2, 0.0)"),
+            CrowEvent::Change {
+                channel: 2,
+                state: false
+            }
+        );
+    }
+
+    // Anything that looks like an event but isn't one we know, or has args we can't read, is kept
+    // raw
+    #[test]
+    fn other_events() {
+        for (line, name, args) in [
+            ("^^pub(\"name\",1)", "pub", "\"name\",1"),
+            ("^^stream(one,0.25)", "stream", "one,0.25"),
+            ("^^change(1)", "change", "1"),
+        ] {
+            assert_eq!(
+                CrowEvent::parse(line),
+                CrowEvent::Event {
+                    name: name.into(),
+                    args: args.into()
+                },
+                "{line}"
+            );
+        }
+    }
+
+    #[test]
+    fn errors_and_lines() {
+        let runtime = "[string \"eval\"]:1: attempt to call a nil value (global 'foo')";
+        assert_eq!(CrowEvent::parse(runtime), CrowEvent::Error(runtime.into()));
+        assert_eq!(
+            CrowEvent::parse(&format!("lua: {runtime}")),
+            CrowEvent::Error(format!("lua: {runtime}"))
+        );
+
+        let compile = "[string \"eval\"]:1: '=' expected near <eof>";
+        assert_eq!(CrowEvent::parse(compile), CrowEvent::Error(compile.into()));
+
+        assert_eq!(CrowEvent::parse("2\n"), CrowEvent::Line("2".into()));
+        // Not closed, so not an event
+        assert_eq!(
+            CrowEvent::parse("^^stream(1,0.25"),
+            CrowEvent::Line("^^stream(1,0.25".into())
+        );
+
+        assert!(CrowEvent::parse("2").is_response());
+        assert!(CrowEvent::parse(runtime).is_response());
+        assert!(!CrowEvent::parse("^^change(2,1)").is_response());
+    }
+}
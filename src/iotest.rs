@@ -0,0 +1,115 @@
+use super::{crow::Crow, error::*, output::Table};
+use std::time::Duration;
+
+// An output patched into an input for the test
+#[derive(Debug, Clone, Copy)]
+pub struct Pair {
+    pub output: u8,
+    pub input: u8,
+}
+
+pub fn parse_pair(s: &str) -> std::result::Result<Pair, String> {
+    let (output, input) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected OUTPUT:INPUT, got '{s}'"))?;
+
+    let output = output
+        .parse()
+        .ok()
+        .filter(|n| (1..=4).contains(n))
+        .ok_or_else(|| format!("output '{output}' should be 1-4"))?;
+    let input = input
+        .parse()
+        .ok()
+        .filter(|n| (1..=2).contains(n))
+        .ok_or_else(|| format!("input '{input}' should be 1-2"))?;
+
+    Ok(Pair { output, input })
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub pairs: Vec<Pair>,
+    pub points: Vec<f64>,
+    pub tolerance: f64,
+    // Time for the output to slew and the input to catch up before reading
+    pub settle: Duration,
+}
+
+async fn read_input(crow: &mut Crow, input: u8) -> Result<f64> {
+    let query = format!("print(input[{input}].volts)");
+    let response = crow.write_and_read(&query).await?.unwrap_or_default();
+
+    response.trim().parse().map_err(|_| Error::Query {
+        query,
+        response: if response.trim().is_empty() {
+            "no response".into()
+        } else {
+            response.trim().into()
+        },
+    })
+}
+
+// Walk each output through the voltage points and check the patched input follows within tolerance.
+// Outputs are returned to 0V afterwards
+pub async fn run(crow: &mut Crow, opts: &Options, color: bool) -> Result<()> {
+    let mut table = Table::new(&["OUTPUT", "INPUT", "SET", "MEASURED", "DELTA", "RESULT"]);
+    let mut summary = Vec::new();
+
+    for pair in &opts.pairs {
+        let mut pair_ok = true;
+        let mut worst: f64 = 0.0;
+
+        for &point in &opts.points {
+            crow.write_all(&format!("output[{}].volts = {point}", pair.output))
+                .await?;
+            tokio::time::sleep(opts.settle).await;
+
+            let measured = read_input(crow, pair.input).await?;
+            let delta = measured - point;
+            let ok = delta.abs() <= opts.tolerance;
+            pair_ok &= ok;
+            worst = worst.max(delta.abs());
+
+            table.row(
+                vec![
+                    pair.output.to_string(),
+                    pair.input.to_string(),
+                    format!("{point:.3}V"),
+                    format!("{measured:.3}V"),
+                    format!("{delta:+.3}V"),
+                    if ok { "pass" } else { "FAIL" }.into(),
+                ],
+                false,
+            );
+        }
+
+        crow.write_all(&format!("output[{}].volts = 0", pair.output))
+            .await?;
+
+        summary.push((
+            format!("out {} -> in {}", pair.output, pair.input),
+            pair_ok,
+            worst,
+        ));
+    }
+
+    print!("{}", table.render(color));
+    println!();
+
+    for (label, ok, worst) in &summary {
+        let result = if *ok { "pass" } else { "FAIL" };
+        println!("{label}: {result} (worst delta {worst:.3}V)");
+    }
+
+    let failed = summary
+        .into_iter()
+        .filter_map(|(label, ok, _)| (!ok).then_some(label))
+        .collect::<Vec<_>>();
+
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::IoTestFailed(failed.join(", ")))
+    }
+}
@@ -0,0 +1,23 @@
+pub mod asl;
+pub mod bridge;
+pub mod complete;
+pub mod config;
+pub mod crow;
+pub mod diff;
+pub mod error;
+pub mod event;
+pub mod iotest;
+pub mod linktest;
+pub mod macros;
+pub mod mock;
+pub mod output;
+pub mod probe;
+pub mod protocol;
+pub mod public;
+pub mod repl;
+pub mod selftest;
+pub mod server;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "ws")]
+pub mod ws;
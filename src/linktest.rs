@@ -0,0 +1,118 @@
+use super::{
+    crow::{Crow, ReconnectPolicy},
+    error::*,
+};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub elapsed: Duration,
+    pub connected: Duration,
+    pub probes: u64,
+    // Probes that got no reply, or the wrong one, without the link dropping
+    pub missed: u64,
+    pub disconnects: u32,
+    pub reconnect_times: Vec<Duration>,
+}
+
+impl Stats {
+    pub fn print(&self) {
+        let secs = self.elapsed.as_secs_f64().max(f64::EPSILON);
+        println!("ran for {:.1}s", self.elapsed.as_secs_f64());
+        println!(
+            "connected: {:.1}s ({:.1}%)",
+            self.connected.as_secs_f64(),
+            100.0 * self.connected.as_secs_f64() / secs
+        );
+        println!("probes: {} sent, {} missed", self.probes, self.missed);
+        println!("disconnects: {}", self.disconnects);
+
+        match self.reconnect_times.len() {
+            0 => println!("average reconnect: n/a"),
+            n => println!(
+                "average reconnect: {:?}",
+                self.reconnect_times.iter().sum::<Duration>() / n as u32
+            ),
+        }
+    }
+}
+
+// Probe the link on an interval, reconnecting whenever it drops, until `duration` passes or Ctrl-C.
+// Missing the deadline while reconnecting ends the run with the link still down
+pub async fn run(
+    crow: &mut Crow,
+    policy: ReconnectPolicy,
+    duration: Option<Duration>,
+    interval: Duration,
+) -> Result<Stats> {
+    let start = Instant::now();
+    let deadline = duration.map(|d| start + d);
+    let expired = || deadline.is_some_and(|deadline| Instant::now() >= deadline);
+
+    let mut stats = Stats::default();
+    let mut connected_since = Some(Instant::now());
+    crow.drain().await?;
+
+    let mut ticker = tokio::time::interval(interval);
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+
+    while !expired() {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = &mut ctrl_c => break,
+        }
+
+        stats.probes += 1;
+        match crow
+            .loopback(&format!("pidgeon-link-{}", stats.probes))
+            .await
+        {
+            Ok(true) => continue,
+            Ok(false) => {
+                stats.missed += 1;
+                continue;
+            }
+            Err(err) => info!(target: "pidgeon::serial", "Probe {} failed: {err}", stats.probes),
+        }
+
+        if let Some(since) = connected_since.take() {
+            stats.connected += since.elapsed();
+        }
+        stats.disconnects += 1;
+        println!("[{:>9.3}s] link dropped", start.elapsed().as_secs_f64());
+
+        let dropped_at = Instant::now();
+        let reconnected = loop {
+            tokio::select! {
+                res = crow.reconnect(policy) => match res {
+                    Ok(()) => break true,
+                    Err(_) if !expired() => continue,
+                    Err(_) => break false,
+                },
+                _ = &mut ctrl_c => break false,
+            }
+        };
+
+        if !reconnected {
+            break;
+        }
+
+        let took = dropped_at.elapsed();
+        println!(
+            "[{:>9.3}s] reconnected after {took:?}",
+            start.elapsed().as_secs_f64()
+        );
+        stats.reconnect_times.push(took);
+        connected_since = Some(Instant::now());
+        crow.drain().await?;
+    }
+
+    if let Some(since) = connected_since {
+        stats.connected += since.elapsed();
+    }
+    stats.elapsed = start.elapsed();
+
+    Ok(stats)
+}
@@ -0,0 +1,38 @@
+use super::{config, error::*};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+// A macros file maps alias names to Lua snippets:
+//
+//   reset = "for i = 1, 4 do output[i].volts = 0 end"
+//   lfo = "output[1].action = lfo(1, 5); output[1]()"
+pub type Macros = BTreeMap<String, String>;
+
+pub fn macros_path() -> Option<PathBuf> {
+    config::config_dir().map(|dir| dir.join("macros.toml"))
+}
+
+pub fn load() -> Result<Macros> {
+    let Some(path) = macros_path() else {
+        return Ok(Macros::new());
+    };
+
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => Ok(toml::from_str(&contents)?),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Macros::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+pub fn lookup(alias: &str) -> Result<String> {
+    let mut macros = load()?;
+
+    macros.remove(alias).ok_or_else(|| Error::UnknownMacro {
+        alias: alias.to_string(),
+        available: if macros.is_empty() {
+            "none defined".to_string()
+        } else {
+            macros.keys().cloned().collect::<Vec<_>>().join(", ")
+        },
+    })
+}
@@ -1,15 +1,21 @@
-mod crow;
-mod error;
-mod repl;
-mod server;
-
 use clap::*;
-use error::*;
+use pidgeon::crow::{
+    self, ConnectInfo, Crow, PortEntry, ReadTimeouts, ReconnectPolicy, Teardown, Upload,
+    UploadOptions,
+};
+use pidgeon::error::{Error, Result};
+use pidgeon::output::{ColorChoice, Format, Table};
+use pidgeon::{
+    asl, bridge, config, diff, iotest, linktest, macros, probe, protocol, public, repl, selftest,
+    server,
+};
+use regex::Regex;
+use std::cell::RefCell;
+use std::net::IpAddr;
 use std::path::PathBuf;
-use tokio_serial::SerialStream;
-use tracing::info;
-
-use crate::crow::Crow;
+use std::time::{Duration, Instant};
+use tracing::{error, info};
+use tracing_subscriber::EnvFilter;
 
 pub const DEFAULT_PORT_STR: &str = "6666";
 pub const DEFAULT_PORT: u16 = 6666;
@@ -20,6 +26,165 @@ pub const DEFAULT_PORT: u16 = 6666;
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    #[command(flatten)]
+    connection: Connection,
+    #[arg(long, global = true, value_enum, default_value_t)]
+    color: ColorChoice,
+    #[arg(long, global = true, value_enum, default_value_t)]
+    format: Format,
+    /// Use the settings in this [profile.<name>] of ~/.config/pidgeon/config.toml as defaults
+    #[arg(long, global = true)]
+    profile: Option<String>,
+}
+
+impl Cli {
+    // Profile values only fill in for flags left at their defaults, anything on the command line wins
+    fn apply_profile(&mut self, matches: &ArgMatches) -> Result<()> {
+        let Some(name) = &self.profile else {
+            return Ok(());
+        };
+        let profile = config::profile(name)?;
+
+        let unset = |id: &str| matches.value_source(id) != Some(parser::ValueSource::CommandLine);
+        let conn = &mut self.connection;
+
+        macro_rules! fill {
+            ($($field:ident => $target:expr),* $(,)?) => {
+                $(if let Some(value) = profile.$field && unset(stringify!($field)) {
+                    $target = value;
+                })*
+            };
+        }
+
+        fill! {
+            strip_echo => conn.strip_echo,
+            half_duplex => conn.half_duplex,
+            delimit_threshold => conn.delimit_threshold,
+            baud => conn.baud,
+            max_reconnect_attempts => conn.max_reconnect_attempts,
+            reconnect_backoff_ms => conn.reconnect_backoff_ms,
+            detect_timeout_ms => conn.detect_timeout_ms,
+            idle_timeout => conn.idle_timeout,
+            response_timeout => conn.response_timeout,
+            reply_timeout => conn.reply_timeout,
+            poll_timeout => conn.poll_timeout,
+            color => self.color,
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Args)]
+struct Connection {
+    /// Drop replies that just echo the command sent, for serial setups with local echo
+    #[arg(long, global = true)]
+    strip_echo: bool,
+    /// Give up reconnecting to a dropped crow after this many tries, 0 keeps trying
+    #[arg(long, global = true, default_value_t = ReconnectPolicy::default().max_attempts)]
+    max_reconnect_attempts: u32,
+    /// A gap this long between lines ends a multi-line response
+    #[arg(long, global = true, value_parser = config::parse_duration, default_value = "300ms")]
+    idle_timeout: Duration,
+    /// Longest to wait for a whole multi-line response, however steadily the crow is printing
+    #[arg(long, global = true, value_parser = config::parse_duration, default_value = "2s")]
+    response_timeout: Duration,
+    /// How long to wait for a single-line reply, e.g. to a command sent through `remote`, which
+    /// answers Failure "timeout" when it runs out
+    #[arg(long, global = true, value_parser = config::parse_duration, default_value = "200ms")]
+    reply_timeout: Duration,
+    /// How long to wait for a line when just checking whether the crow printed anything
+    #[arg(long, global = true, value_parser = config::parse_duration, default_value = "10ms")]
+    poll_timeout: Duration,
+    /// Open this serial device instead of detecting the crow, e.g. /dev/ttyACM0
+    #[arg(long = "port", value_name = "PATH", global = true)]
+    device: Option<String>,
+    /// Pick the crow with this USB serial number, when more than one is connected
+    #[arg(long, global = true, conflicts_with_all = ["device", "index"])]
+    serial: Option<String>,
+    /// Pick a crow by its position in `pidgeon list`, when more than one is connected
+    #[arg(long, global = true, conflicts_with = "device")]
+    index: Option<usize>,
+    /// Serial speed, for older bootloaders or custom firmware
+    #[arg(long, global = true, default_value_t = crow::DEFAULT_BAUD, value_parser = parse_baud)]
+    baud: u32,
+    /// Never read while writing, for serial adapters that garble responses arriving right after a send
+    #[arg(long, global = true)]
+    half_duplex: bool,
+    /// Fence lines longer than this many bytes in ```, 0 fences every line
+    #[arg(long, global = true, default_value_t = crow::LINE_LIMIT)]
+    delimit_threshold: usize,
+    /// Drop DTR after opening the port, for hubs or drivers that misbehave with it raised
+    #[arg(long, global = true)]
+    no_dtr: bool,
+    /// When the command is done with the crow, however it ended, leave it with its script cleared
+    /// or restarted
+    #[arg(long, global = true, value_enum)]
+    teardown: Option<Teardown>,
+    /// Keep looking for a crow for this long before giving up, e.g. 10 or 30s, for crows that are
+    /// still showing up on USB
+    #[arg(long, global = true, value_parser = config::parse_duration)]
+    wait: Option<Duration>,
+    /// Give up on finding a crow if listing serial ports takes longer than this
+    #[arg(long, global = true, default_value_t = crow::DETECT_TIMEOUT.as_millis() as u64)]
+    detect_timeout_ms: u64,
+    /// Delay between reconnect attempts
+    #[arg(long, global = true, default_value_t = ReconnectPolicy::default().backoff.as_millis() as u64)]
+    reconnect_backoff_ms: u64,
+}
+
+impl Connection {
+    fn read_timeouts(&self) -> ReadTimeouts {
+        ReadTimeouts {
+            idle: self.idle_timeout,
+            response: self.response_timeout,
+        }
+    }
+
+    fn reconnect_policy(&self) -> ReconnectPolicy {
+        ReconnectPolicy {
+            max_attempts: self.max_reconnect_attempts,
+            backoff: Duration::from_millis(self.reconnect_backoff_ms),
+            ..Default::default()
+        }
+    }
+}
+
+// Each crow a command connected to, so --teardown can find them again once it's done, however it
+// finished and whatever the crow was handed to along the way
+#[derive(Default)]
+struct Opened(RefCell<Vec<ConnectInfo>>);
+
+async fn connect(opts: &Connection, opened: &Opened) -> Result<Crow> {
+    let mut crow = match &opts.device {
+        Some(path) => Crow::with_baud(path, opts.baud)?,
+        None => {
+            let select = match (&opts.serial, opts.index) {
+                (Some(serial), _) => crow::Select::Serial(serial.clone()),
+                (None, Some(index)) => crow::Select::Index(index),
+                (None, None) => crow::Select::Only,
+            };
+
+            let detect_timeout = Duration::from_millis(opts.detect_timeout_ms);
+            match opts.wait {
+                Some(wait) => Crow::wait_for(wait, detect_timeout, select, opts.baud).await?,
+                None => Crow::detect(detect_timeout, select, opts.baud).await?,
+            }
+        }
+    };
+    crow.set_strip_echo(opts.strip_echo);
+    crow.set_half_duplex(opts.half_duplex);
+    crow.set_delimit_threshold(opts.delimit_threshold);
+    crow.set_reply_timeouts(opts.reply_timeout, opts.poll_timeout);
+    if opts.no_dtr {
+        crow.set_dtr(false)?;
+    }
+    info!(target: "pidgeon::serial", "Connected to {:?}", crow.info());
+    if opts.teardown.is_some() {
+        opened.0.borrow_mut().push(crow.info().clone());
+    }
+
+    Ok(crow)
 }
 
 #[derive(Debug, Subcommand)]
@@ -27,69 +192,943 @@ enum Commands {
     #[command(arg_required_else_help = true)]
     File {
         path: PathBuf,
+        /// Write the script to flash so it runs on boot, replacing the stored script
+        #[arg(long)]
+        flash: bool,
+        /// Don't ask before overwriting the stored script
+        #[arg(long)]
+        yes: bool,
+        /// Don't send a newline after the end-of-script marker
+        #[arg(long)]
+        no_trailing_newline: bool,
+        /// Send scripts larger than this in pieces of this many bytes
+        #[arg(long, default_value_t = UploadOptions::default().chunk_size)]
+        chunk_size: usize,
+        /// Pause between pieces of a chunked upload
+        #[arg(long, value_parser = config::parse_duration, default_value = "5ms")]
+        chunk_delay: Duration,
+    },
+    Repl {
+        #[command(flatten)]
+        wrap: repl::Wrap,
+        /// Write the Lua typed during the session to this file on exit, leaving out repl commands
+        /// like .run and .load
+        #[arg(long)]
+        save_on_exit: Option<PathBuf>,
+        /// Keep line history here across sessions, instead of $XDG_DATA_HOME/pidgeon/history or
+        /// ~/.pidgeon_history
+        #[arg(long)]
+        history_file: Option<PathBuf>,
+        /// Don't highlight the Lua as it's typed, for dumb terminals. Also off with --color never
+        #[arg(long)]
+        no_color: bool,
     },
-    Repl,
     Remote {
         #[arg(default_value = DEFAULT_PORT_STR)]
         port: Option<u16>,
+        /// Address to listen on. Clients can run any Lua on the crow and there's no
+        /// authentication, so only use 0.0.0.0 or a LAN address on a network you trust
+        #[arg(long, default_value_t = server::DEFAULT_BIND)]
+        bind: IpAddr,
+        /// Listen on a unix socket at this path instead of TCP, for clients on this machine
+        #[cfg(unix)]
+        #[arg(long, conflicts_with_all = ["port", "bind"])]
+        unix: Option<PathBuf>,
+        /// Encrypt client connections with TLS, worth it once the server is reachable past localhost
+        #[cfg(feature = "tls")]
+        #[arg(long, requires_all = ["cert", "key"])]
+        #[cfg_attr(unix, arg(conflicts_with = "unix"))]
+        tls: bool,
+        /// PEM certificate chain for --tls
+        #[cfg(feature = "tls")]
+        #[arg(long, requires = "tls")]
+        cert: Option<PathBuf>,
+        /// PEM private key for --tls
+        #[cfg(feature = "tls")]
+        #[arg(long, requires = "tls")]
+        key: Option<PathBuf>,
+        /// Log every message clients send, for debugging integrations
+        #[arg(long)]
+        log_requests: bool,
+        /// Largest message in bytes a client may send, bigger ones get a Failure and are disconnected
+        #[arg(long, default_value_t = protocol::BUFSIZE)]
+        max_message_size: usize,
+        /// Give the crow to one client at a time and turn others away until it stops, instead of
+        /// interleaving everyone's requests
+        #[arg(long)]
+        exclusive: bool,
+        /// Only serve clients that send this shared secret first, for servers others can reach
+        #[arg(long)]
+        auth_token: Option<String>,
+        /// Ping clients that have been quiet this long, e.g. 30s, to catch links that died silently
+        #[arg(long, value_parser = config::parse_duration)]
+        heartbeat: Option<Duration>,
+        /// How long a client gets to answer a heartbeat before it's disconnected
+        #[arg(
+            long,
+            value_parser = config::parse_duration,
+            default_value = "10s",
+            requires = "heartbeat"
+        )]
+        heartbeat_timeout: Duration,
     },
     Simulate,
+    /// Run the server against a simulated crow and check a client session end to end
+    #[command(hide = true)]
+    SelfTest {
+        /// Run the server on this address, e.g. ::1 to check IPv6
+        #[arg(long, default_value_t = server::DEFAULT_BIND)]
+        bind: IpAddr,
+    },
+    /// Send a line of Lua and print the response
+    #[command(arg_required_else_help = true)]
+    Exec {
+        code: String,
+        /// Re-run the command on an interval, e.g. 500ms, 1s, 2m
+        #[arg(long, value_parser = config::parse_period)]
+        every: Option<Duration>,
+        /// Stop after this many runs, runs until Ctrl-C if absent
+        #[arg(long, requires = "every")]
+        count: Option<u64>,
+        /// Keep printing crow output after the response, until Ctrl-C
+        #[arg(long, conflicts_with = "every")]
+        follow: bool,
+        /// End the session when a line matches this regex (or literal text), implies --follow
+        #[arg(long, conflicts_with = "every")]
+        stop_on: Option<String>,
+        #[command(flatten)]
+        wrap: repl::Wrap,
+    },
+    /// Report the crow's Lua memory usage
+    Mem {
+        /// Keep polling on an interval, 1s if no interval is given
+        #[arg(long, value_parser = config::parse_period, num_args = 0..=1, default_missing_value = "1s")]
+        watch: Option<Duration>,
+    },
+    /// Upload progressively larger scripts to find the largest one the crow handles
+    ProbeLimits {
+        /// Required, this sends the crow scripts full of junk
+        #[arg(long)]
+        yes: bool,
+        /// Largest script to try, in bytes
+        #[arg(long, default_value_t = 256 * 1024)]
+        max: usize,
+    },
+    /// Print everything the crow says
+    Monitor {
+        /// Print output in batches of this many lines, for scripts that print a lot
+        #[arg(long)]
+        buffer_lines: Option<usize>,
+    },
+    /// Start one of crow's ASL actions on an output
+    #[command(subcommand)]
+    Asl(asl::Action),
+    /// Stop everything now: clear the running script and set every output to 0V
+    Panic,
+    /// Print pidgeon's version and the connected crow's firmware version
+    Version,
+    /// Experimental: mirror an input on one crow to an output on another
+    Bridge {
+        /// Device path of the crow to read from
+        #[arg(long)]
+        from: String,
+        /// Device path of the crow to write to
+        #[arg(long)]
+        to: String,
+        /// Input on the source crow
+        #[arg(long = "in", default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=2))]
+        input: u8,
+        /// Output on the destination crow
+        #[arg(long = "out", default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=4))]
+        output: u8,
+        /// How often the source input is sampled
+        #[arg(long, value_parser = config::parse_duration, default_value = "10ms")]
+        interval: Duration,
+    },
+    /// Check outputs patched into inputs track each other, e.g. after building or repairing a crow
+    IoTest {
+        /// OUTPUT:INPUT patched together, repeatable
+        #[arg(long = "pair", value_parser = iotest::parse_pair, default_values = ["1:1", "2:2"])]
+        pairs: Vec<iotest::Pair>,
+        /// Voltages to set each output to
+        #[arg(long, value_delimiter = ',', default_values_t = [-5.0, -2.5, 0.0, 2.5, 5.0, 10.0])]
+        points: Vec<f64>,
+        /// Largest acceptable difference between set and measured, in volts
+        #[arg(long, default_value_t = 0.05)]
+        tolerance: f64,
+        /// How long to let each output settle before reading the input
+        #[arg(long, value_parser = config::parse_duration, default_value = "50ms")]
+        settle: Duration,
+    },
+    /// Keep probing the crow, reconnecting when it drops, then report uptime and disconnects
+    LinkTest {
+        /// How long to run, e.g. 30s, 10m, runs until Ctrl-C if absent
+        #[arg(long, value_parser = config::parse_duration)]
+        duration: Option<Duration>,
+        /// Time between probes
+        #[arg(long, value_parser = config::parse_period, default_value = "500ms")]
+        interval: Duration,
+    },
+    /// Print the script stored on the crow, or save it with --out
+    Dump {
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Compare the script stored on the crow against a local file
+    #[command(arg_required_else_help = true)]
+    Diff {
+        path: PathBuf,
+    },
+    /// Work with the parameters a script exposes through `public`
+    #[command(subcommand)]
+    Public(PublicAction),
+    /// Run the script stored in flash, without re-uploading it, and print its output
+    Run {
+        /// Don't wait for the crow's output, just start the script
+        #[arg(long)]
+        quiet: bool,
+    },
+    /// Restart the crow and print its boot banner
+    ///
+    /// Crow can only restart by running the script stored in flash again, so this sends the same
+    /// ^^r as `run`
+    Restart {
+        /// Don't wait for the crow to come back up
+        #[arg(long)]
+        quiet: bool,
+    },
+    /// Run First, the script built into crow's firmware, leaving the stored script in place
+    First {
+        /// Don't wait for the crow's output
+        #[arg(long)]
+        quiet: bool,
+    },
+    /// Reboot the crow into its bootloader for firmware updates
+    Bootloader {
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Erase the user script stored in the crow's flash
+    #[command(visible_alias = "clear")]
+    ClearFlash {
+        #[arg(long)]
+        yes: bool,
+    },
+    /// List serial devices, highlighting any that look like a crow
+    List,
+    /// Diagnose crow detection problems
+    Doctor,
+    /// Stream crow output to browsers over a websocket, as JSON events
+    #[cfg(feature = "ws")]
+    Ws {
+        /// Port to serve the websocket on, --port picks the crow's serial device
+        #[arg(long, default_value_t = 8080)]
+        listen: u16,
+        /// Let pages from this origin connect, e.g. http://localhost:3000, repeat for more. Any
+        /// page open in the browser could otherwise send the crow Lua, so nothing is let in
+        /// without it. "*" lets in every page and clients that send no Origin
+        #[arg(long = "allow-origin", value_name = "ORIGIN")]
+        allow_origins: Vec<String>,
+    },
+    /// Send a snippet from ~/.config/pidgeon/macros.toml
+    #[command(arg_required_else_help = true)]
+    Macro {
+        alias: String,
+    },
 }
 use Commands::*;
 
+#[derive(Debug, Subcommand)]
+enum PublicAction {
+    /// List every public parameter the running script declared, with its current value
+    Dump,
+}
+
 #[tokio::main]
-async fn main() -> std::io::Result<()> {
-    tracing_subscriber::fmt::init();
+async fn main() -> std::process::ExitCode {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+
+    let matches = Cli::command().get_matches();
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+
+    let res = match cli.apply_profile(&matches) {
+        Ok(()) => run(cli).await,
+        Err(err) => Err(err),
+    };
 
-    app(Cli::parse().command)
-        .await
-        .map_err(std::io::Error::other)
+    match res {
+        Ok(()) => std::process::ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {err}");
+            std::process::ExitCode::FAILURE
+        }
+    }
 }
 
-async fn app(command: Commands) -> Result<()> {
+// The command, then the teardown. An error from the command is the one reported, a teardown that
+// also fails after it is only logged
+async fn run(cli: Cli) -> Result<()> {
+    let (teardown, no_dtr) = (cli.connection.teardown, cli.connection.no_dtr);
+    let opened = Opened::default();
+    let res = app(cli, &opened).await;
+
+    let Some(teardown) = teardown else {
+        return res;
+    };
+    // The command's own connection is closed by now, so each crow is opened again just for this
+    let once = ReconnectPolicy {
+        max_attempts: 1,
+        ..Default::default()
+    };
+    let mut torn_down = Ok(());
+    for info in opened.0.take() {
+        let leave = async {
+            let mut crow = Crow::reopen(&info, once).await?;
+            if no_dtr {
+                crow.set_dtr(false)?;
+            }
+            crow.shutdown(teardown).await
+        };
+        if let Err(err) = leave.await {
+            error!(
+                target: "pidgeon::serial",
+                "Couldn't leave the crow at {} {teardown:?}: {err}",
+                info.path
+            );
+            torn_down = torn_down.and(Err(err));
+        }
+    }
+
+    res.and(torn_down)
+}
+
+async fn app(
+    Cli {
+        command,
+        connection,
+        color,
+        format,
+        ..
+    }: Cli,
+    opened: &Opened,
+) -> Result<()> {
     match command {
-        File { path } => {
-            let crow = Crow::new()?;
+        File {
+            path,
+            flash,
+            yes,
+            no_trailing_newline,
+            chunk_size,
+            chunk_delay,
+        } => {
+            let contents = String::from_utf8(std::fs::read(&path)?)
+                .map_err(|_| Error::NotUtf8(path.clone()))?;
+
+            if flash {
+                ensure_confirmed("This will overwrite the script stored on the crow", yes)?;
+            }
+
+            let crow = connect(&connection, opened).await?;
+            let (mut reader, mut writer) = crow.split();
+            reader.drain().await?;
+
+            let opts = UploadOptions {
+                flash,
+                trailing_newline: !no_trailing_newline,
+                chunk_size,
+                chunk_delay,
+            };
+            writer.upload(contents.as_str(), opts).await?;
+
+            match crow::confirm_upload(&mut reader, connection.response_timeout).await? {
+                Upload::Running { lines } => lines.iter().for_each(|line| println!("{line}")),
+                Upload::CompileError(message) => return Err(Error::ScriptCompile { message }),
+                Upload::Unconfirmed { lines } => {
+                    lines.iter().for_each(|line| println!("{line}"));
+                    eprintln!(
+                        "crow didn't confirm the upload within {:?}",
+                        connection.response_timeout
+                    );
+                }
+            }
+
+            Ok(())
+        }
+        Repl {
+            wrap,
+            save_on_exit,
+            history_file,
+            no_color,
+        } => {
+            let opts = repl::Options {
+                wrap,
+                save_on_exit,
+                history_file: history_file.or_else(config::history_path),
+                color: !no_color && color.enabled(),
+                timeouts: connection.read_timeouts(),
+                reconnect: connection.reconnect_policy(),
+            };
+
+            repl::run(connect(&connection, opened).await?, &opts).await
+        }
+        Exec {
+            code,
+            every,
+            count,
+            follow,
+            stop_on,
+            wrap,
+        } => {
+            let code = wrap.apply(&code);
+            let stop_on = stop_on
+                .map(|pattern| {
+                    Regex::new(&pattern).or_else(|_| Regex::new(&regex::escape(&pattern)))
+                })
+                .transpose()
+                .expect("escaped pattern is a valid regex");
+
+            let mut crow = connect(&connection, opened).await?;
+
+            match every {
+                None if follow || stop_on.is_some() => {
+                    crow.write_line(&code).await?;
+
+                    tokio::select! {
+                        res = follow_output(&mut crow, stop_on.as_ref()) => res,
+                        _ = tokio::signal::ctrl_c() => Ok(()),
+                    }
+                }
+                None if format == Format::Json => {
+                    let reply = crow
+                        .write_and_ack(&code, connection.response_timeout)
+                        .await?;
+                    let response = (!reply.lines.is_empty()).then(|| reply.lines.join("\n"));
+                    let compile_error = response.as_deref().is_some_and(crow::is_compile_error);
+
+                    println!(
+                        "{}",
+                        serde_json::json!({
+                            "ok": !compile_error,
+                            "response": response,
+                            "timed_out": !reply.acked,
+                        })
+                    );
+
+                    Ok(())
+                }
+                None => match crow.write_and_read(&code).await? {
+                    Some(response) if crow::is_compile_error(&response) => {
+                        Err(Error::ScriptCompile {
+                            message: response.trim_end().into(),
+                        })
+                    }
+                    Some(response) => {
+                        println!("{}", response.trim_end());
+                        Ok(())
+                    }
+                    None => Ok(()),
+                },
+                Some(every) => {
+                    tokio::select! {
+                        res = exec_every(&mut crow, &code, every, count) => res,
+                        _ = tokio::signal::ctrl_c() => Ok(()),
+                    }
+                }
+            }
+        }
+        Mem { watch } => {
+            let mut crow = connect(&connection, opened).await?;
+
+            match watch {
+                None => {
+                    println!("lua memory: {:.1} KB", crow.memory_kb().await?);
+                    Ok(())
+                }
+                Some(every) => {
+                    let mut interval = tokio::time::interval(every);
+                    loop {
+                        tokio::select! {
+                            _ = interval.tick() => println!("lua memory: {:.1} KB", crow.memory_kb().await?),
+                            _ = tokio::signal::ctrl_c() => break Ok(()),
+                        }
+                    }
+                }
+            }
+        }
+        ProbeLimits { yes, max } => {
+            ensure_confirmed(
+                "This will upload junk scripts until the crow fails, replacing the running script",
+                yes,
+            )?;
+
+            let mut crow = connect(&connection, opened).await?;
+            match probe::probe_limits(&mut crow, max).await? {
+                Some(size) => println!("largest clean upload: {size} bytes"),
+                None => println!("no probe uploaded cleanly"),
+            }
+
+            Ok(())
+        }
+        Monitor { buffer_lines } => {
+            let (reader, _writer) = connect(&connection, opened).await?.split();
+
+            tokio::select! {
+                res = async {
+                    match buffer_lines {
+                        Some(batch) => reader.run_buffered(batch.max(1)).await,
+                        None => {
+                            reader.run(Some(connection.reconnect_policy())).await;
+                            Ok(())
+                        }
+                    }
+                } => Ok(res?),
+                _ = tokio::signal::ctrl_c() => Ok(()),
+            }
+        }
+        Asl(action) => {
+            let mut crow = connect(&connection, opened).await?;
+
+            if let Some(response) = crow.write_and_read(&action.lua()).await? {
+                println!("{}", response.trim_end());
+            }
+
+            Ok(())
+        }
+        Panic => connect(&connection, opened).await?.panic().await,
+        Bridge {
+            from,
+            to,
+            input,
+            output,
+            interval,
+        } => {
+            bridge::run(
+                Crow::with_baud(&from, connection.baud)?,
+                Crow::with_baud(&to, connection.baud)?,
+                input,
+                output,
+                interval,
+            )
+            .await
+        }
+        IoTest {
+            pairs,
+            points,
+            tolerance,
+            settle,
+        } => {
+            println!("patch each output into its input before running:");
+            for pair in &pairs {
+                println!("  output {} -> input {}", pair.output, pair.input);
+            }
+            println!();
+
+            let opts = iotest::Options {
+                pairs,
+                points,
+                tolerance,
+                settle,
+            };
+
+            iotest::run(
+                &mut connect(&connection, opened).await?,
+                &opts,
+                color.enabled(),
+            )
+            .await
+        }
+        LinkTest { duration, interval } => {
+            let mut crow = connect(&connection, opened).await?;
+            let stats =
+                linktest::run(&mut crow, connection.reconnect_policy(), duration, interval).await?;
+            stats.print();
+
+            Ok(())
+        }
+        #[cfg(feature = "ws")]
+        Ws {
+            listen,
+            allow_origins,
+        } => pidgeon::ws::run(connect(&connection, opened).await?, listen, allow_origins).await,
+        Dump { out } => {
+            let mut crow = connect(&connection, opened).await?;
+            let mut script = crow.read_script(connection.read_timeouts()).await?;
+            script.push('\n');
+
+            match out {
+                Some(path) => {
+                    std::fs::write(&path, &script)?;
+                    println!("saved {} bytes to {}", script.len(), path.display());
+                }
+                None => print!("{script}"),
+            }
+
+            Ok(())
+        }
+        Diff { path } => {
+            let mut crow = connect(&connection, opened).await?;
+            diff::run(
+                &mut crow,
+                &path,
+                connection.read_timeouts(),
+                color.enabled(),
+            )
+            .await
+        }
+        Version => {
+            let pidgeon = env!("CARGO_PKG_VERSION");
+
+            if format == Format::Json {
+                let firmware = connect(&connection, opened).await?.version().await?;
+                println!(
+                    "{}",
+                    serde_json::json!({ "pidgeon": pidgeon, "firmware": firmware })
+                );
+            } else {
+                // Printed first so it shows even when there's no crow to ask
+                println!("pidgeon {pidgeon}");
+                println!(
+                    "crow {}",
+                    connect(&connection, opened).await?.version().await?
+                );
+            }
+
+            Ok(())
+        }
+        Public(PublicAction::Dump) => {
+            let mut crow = connect(&connection, opened).await?;
+            let params = public::discover(&mut crow, connection.read_timeouts()).await?;
+
+            if format == Format::Json {
+                println!("{}", serde_json::to_string(&params)?);
+            } else if params.is_empty() {
+                println!("the running script has no public parameters");
+            } else {
+                let mut table = Table::new(&["NAME", "KIND", "VALUE"]);
+                for param in params {
+                    table.row(vec![param.name, param.kind, param.value], false);
+                }
+                print!("{}", table.render(color.enabled()));
+            }
+
+            Ok(())
+        }
+        Run { quiet } => {
+            let (mut reader, mut writer) = connect(&connection, opened).await?.split();
+
+            writer.run_flash_script().await?;
+            if !quiet {
+                crow::read_response(&mut reader, connection.read_timeouts(), |line| {
+                    println!("{line}")
+                })
+                .await?;
+            }
+
+            Ok(())
+        }
+        Restart { quiet } => {
+            let (mut reader, mut writer) = connect(&connection, opened).await?.split();
+
+            writer.restart().await?;
+            if !quiet {
+                crow::read_response(&mut reader, connection.read_timeouts(), |line| {
+                    println!("{line}")
+                })
+                .await?;
+            }
+
+            Ok(())
+        }
+        First { quiet } => {
+            let (mut reader, mut writer) = connect(&connection, opened).await?.split();
+
+            writer.run_first().await?;
+            if !quiet {
+                crow::read_response(&mut reader, connection.read_timeouts(), |line| {
+                    println!("{line}")
+                })
+                .await?;
+            }
+
+            Ok(())
+        }
+        Bootloader { yes } => {
+            ensure_confirmed("This will reboot the crow into its bootloader", yes)?;
+
+            // Dropped straight away, nothing reads from a port that's about to disappear
+            connect(&connection, opened)
+                .await?
+                .enter_bootloader()
+                .await?;
+            println!(
+                "crow is rebooting into its bootloader, the serial connection will drop and it will \
+                 show up as a DFU device, ready for e.g. dfu-util"
+            );
+
+            Ok(())
+        }
+        ClearFlash { yes } => {
+            ensure_confirmed("This will erase the script stored on the crow", yes)?;
+
+            connect(&connection, opened).await?.clear_script().await?;
+            println!("cleared the stored script");
+
+            Ok(())
+        }
+        List => {
+            print_ports(&crow::ports()?, format, color);
+            Ok(())
+        }
+        Doctor => doctor(format, color, connection.baud),
+        Macro { alias } => {
+            let snippet = macros::lookup(&alias)?;
+
+            let crow = connect(&connection, opened).await?;
             let (mut reader, mut writer) = crow.split();
 
-            let contents = std::fs::read_to_string(path)?;
-            writer.write_script(contents.as_str()).await?;
+            writer.write_line(&snippet).await?;
 
             let response = reader.read_once().await?;
             println!("{response}");
 
             Ok(())
         }
-        Repl => {
-            let crow = Crow::new()?;
-            let (reader, writer) = crow.split();
-            let _reader_handle = tokio::spawn(reader.run());
+        Remote {
+            port,
+            bind,
+            log_requests,
+            max_message_size,
+            exclusive,
+            auth_token,
+            heartbeat,
+            heartbeat_timeout,
+            #[cfg(unix)]
+            unix,
+            #[cfg(feature = "tls")]
+            tls,
+            #[cfg(feature = "tls")]
+            cert,
+            #[cfg(feature = "tls")]
+            key,
+        } => {
+            let config = server::Config {
+                bind,
+                port: port.unwrap_or(DEFAULT_PORT),
+                reconnect: connection.reconnect_policy(),
+                log_requests,
+                max_message: max_message_size,
+                exclusive,
+                heartbeat,
+                heartbeat_timeout,
+                response: connection.read_timeouts(),
+                auth_token,
+            };
 
-            repl::run(writer).await
+            let crow = connect(&connection, opened).await?;
+
+            #[cfg(unix)]
+            if let Some(path) = unix {
+                return server::run_unix(crow, &path, config).await;
+            }
+
+            #[cfg(feature = "tls")]
+            if let (true, Some(cert), Some(key)) = (tls, &cert, &key) {
+                let acceptor = pidgeon::tls::acceptor(cert, key)?;
+                return server::run_tls(crow, config, acceptor).await;
+            }
+
+            server::run(crow, config).await
         }
-        Remote { port } => server::run(Crow::new()?, port.unwrap_or(DEFAULT_PORT)).await,
         Simulate => {
-            let (leader, mut follower) = SerialStream::pair()?;
-            let crow = Crow::mock(leader);
-            let handle = tokio::spawn(async move {
-                use tokio::io::{AsyncReadExt, AsyncWriteExt};
-
-                let mut buf = Vec::with_capacity(1024);
-                loop {
-                    if AsyncReadExt::read(&mut follower, &mut buf).await.is_ok() {
-                        info!("Mock crow got: '{}'", String::from_utf8_lossy(&buf));
-                        follower
-                            .write_all(b"OK")
-                            .await
-                            .expect("Failed to write dummy stream");
-                    }
-                }
-            });
+            let (crow, handle) = Crow::simulated()?;
+
+            let config = server::Config {
+                bind: server::DEFAULT_BIND,
+                port: DEFAULT_PORT,
+                reconnect: connection.reconnect_policy(),
+                log_requests: true,
+                max_message: protocol::BUFSIZE,
+                exclusive: false,
+                heartbeat: None,
+                heartbeat_timeout: server::DEFAULT_HEARTBEAT_TIMEOUT,
+                response: connection.read_timeouts(),
+                auth_token: None,
+            };
 
-            server::run(crow, DEFAULT_PORT).await?;
+            server::run(crow, config).await?;
             handle.abort();
 
             Ok(())
         }
+        SelfTest { bind } => selftest::run(bind).await,
+    }
+}
+
+// Ask before doing something destructive. Without a terminal to ask on, only --yes goes ahead
+fn confirm(prompt: &str, assume_yes: bool) -> bool {
+    use std::io::{BufRead, IsTerminal, Write};
+
+    if assume_yes {
+        return true;
+    }
+
+    if !std::io::stdin().is_terminal() {
+        return false;
+    }
+
+    print!("{prompt}. Continue? [y/N] ");
+    let _ = std::io::stdout().flush();
+
+    let mut answer = String::new();
+    if std::io::stdin().lock().read_line(&mut answer).is_err() {
+        return false;
+    }
+
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+fn ensure_confirmed(effect: &'static str, assume_yes: bool) -> Result<()> {
+    if confirm(effect, assume_yes) {
+        Ok(())
+    } else {
+        Err(Error::NeedsConfirmation(effect))
+    }
+}
+
+fn parse_baud(s: &str) -> std::result::Result<u32, String> {
+    let baud = s.parse().map_err(|_| format!("invalid baud rate '{s}'"))?;
+    if crow::STANDARD_BAUDS.contains(&baud) {
+        Ok(baud)
+    } else {
+        Err(format!(
+            "{baud} isn't a standard rate, expected one of {}",
+            crow::STANDARD_BAUDS.map(|b| b.to_string()).join(", ")
+        ))
+    }
+}
+
+async fn follow_output(crow: &mut Crow, stop_on: Option<&Regex>) -> Result<()> {
+    loop {
+        let line = crow.read_line().await?;
+        // Even a blank line ends in a newline, nothing at all means the port's closed
+        if line.is_empty() {
+            break Err(Error::ConnectionClosed);
+        }
+        let line = line.trim_end();
+        println!("{line}");
+
+        if stop_on.is_some_and(|pattern| pattern.is_match(line)) {
+            info!(target: "pidgeon::serial", "Stop pattern matched, ending session");
+            break Ok(());
+        }
+    }
+}
+
+async fn exec_every(
+    crow: &mut Crow,
+    code: &str,
+    every: Duration,
+    count: Option<u64>,
+) -> Result<()> {
+    let start = Instant::now();
+    let mut interval = tokio::time::interval(every);
+
+    let mut runs = 0;
+    while count.is_none_or(|count| runs < count) {
+        interval.tick().await;
+        runs += 1;
+
+        let response = crow.write_and_read(code).await?;
+        println!(
+            "[{:>9.3}s] {}",
+            start.elapsed().as_secs_f64(),
+            response.as_deref().unwrap_or("").trim_end()
+        );
+    }
+
+    Ok(())
+}
+
+fn print_ports(ports: &[PortEntry], format: Format, color: ColorChoice) {
+    match format {
+        Format::Json => println!("{}", serde_json::json!(ports)),
+        Format::Text => {
+            let mut table = Table::new(&["DEVICE", "TYPE", "PRODUCT", "SERIAL", "CROW"]);
+            for port in ports {
+                table.row(
+                    vec![
+                        port.path.clone(),
+                        port.kind.to_string(),
+                        port.product.clone().unwrap_or_default(),
+                        port.serial.clone().unwrap_or_default(),
+                        port.index
+                            .map(|index| format!("yes, #{index}"))
+                            .unwrap_or_default(),
+                    ],
+                    port.crow,
+                );
+            }
+            print!("{}", table.render(color.enabled()));
+        }
     }
 }
+
+fn doctor(format: Format, color: ColorChoice, baud: u32) -> Result<()> {
+    let ports = crow::ports()?;
+    let candidates = ports.iter().filter(|port| port.crow).collect::<Vec<_>>();
+
+    let opened = candidates.first().map(|port| {
+        tokio_serial::new(&port.path, baud)
+            .open()
+            .map(|_| ())
+            .map_err(|err| err.to_string())
+    });
+
+    if format == Format::Json {
+        println!(
+            "{}",
+            serde_json::json!({
+                "ports": ports,
+                "candidates": candidates.len(),
+                "opened": opened.as_ref().map(|res| res.is_ok()),
+                "error": opened.and_then(|res| res.err()),
+            })
+        );
+        return Ok(());
+    }
+
+    print_ports(&ports, format, color);
+    println!();
+
+    match candidates.len() {
+        0 => {
+            println!("no crow found");
+            if ports.iter().any(|port| port.kind == "usb") {
+                println!(
+                    "crow is detected by its product string \"{}\" or its USB ID {:04x}:{:04x}, \
+                     and none of the USB devices above has either",
+                    crow::CROW_PRODUCT,
+                    crow::CROW_VID,
+                    crow::CROW_PID
+                );
+                println!("if one of them is the crow anyway, open it with --port");
+            } else {
+                println!("check the USB cable, some cables only carry power");
+            }
+        }
+        1 => println!("found a crow at {}", candidates[0].path),
+        n => println!("found {n} crows, pick one with --serial, --index or --port"),
+    }
+
+    match opened {
+        Some(Ok(())) => println!("opened {} fine", candidates[0].path),
+        Some(Err(err)) => println!("couldn't open {}: {err}", candidates[0].path),
+        None => {}
+    }
+
+    Ok(())
+}
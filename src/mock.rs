@@ -0,0 +1,325 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_serial::SerialStream;
+use tracing::info;
+
+const BANNER: &str = " hi from crow! (simulated)";
+const VERSION: &str = "v4.0.0-sim";
+// What `collectgarbage('count')` reports, roughly a freshly booted crow
+const MEMORY_KB: f64 = 31.5;
+
+// A stand-in for crow's side of the serial protocol, good enough to drive pidgeon end to end.
+// Understands the ^^ commands, ^^s...^^e uploads and ``` fences, and evaluates the handful of Lua
+// pidgeon itself sends. Anything else is taken as a statement that prints nothing
+#[derive(Debug, Default)]
+pub struct MockCrow {
+    // Bytes not yet making up a whole command
+    pending: Vec<u8>,
+    // Set between ^^s and ^^e/^^w
+    uploading: bool,
+    // Set between a pair of ```
+    fenced: bool,
+    // What ^^w last wrote to "flash"
+    stored: Option<String>,
+}
+
+impl MockCrow {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Take whatever arrived over the wire and return the lines crow would print in reply
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<String> {
+        self.pending.extend_from_slice(bytes);
+        let mut out = Vec::new();
+
+        loop {
+            if self.uploading {
+                let Some((at, marker)) = find_any(&self.pending, &[b"^^e", b"^^w"]) else {
+                    break;
+                };
+                let script = String::from_utf8_lossy(&self.pending[..at]).into_owned();
+                self.pending.drain(..at + marker.len());
+                self.uploading = false;
+
+                if marker == b"^^w" {
+                    self.stored = Some(script);
+                    out.push("User script updated.".into());
+                }
+                out.push("Running: user script".into());
+            } else if self.fenced {
+                let Some(at) = find(&self.pending, b"```") else {
+                    break;
+                };
+                let chunk = String::from_utf8_lossy(&self.pending[..at]).into_owned();
+                self.pending.drain(..at + 3);
+                self.fenced = false;
+
+                out.extend(eval(&chunk));
+            } else if self.pending.starts_with(b"^^s") {
+                self.pending.drain(..3);
+                self.uploading = true;
+            } else if self.pending.starts_with(b"```") {
+                self.pending.drain(..3);
+                self.fenced = true;
+            } else {
+                let Some(at) = find(&self.pending, b"\n") else {
+                    break;
+                };
+                let line = String::from_utf8_lossy(&self.pending[..at]).into_owned();
+                self.pending.drain(..=at);
+
+                out.extend(self.command(line.trim_end_matches('\r')));
+            }
+        }
+
+        out
+    }
+
+    fn command(&mut self, line: &str) -> Vec<String> {
+        match line.trim() {
+            "" => vec![],
+            "^^v" => vec![format!("^^version('{VERSION}')")],
+            "^^i" => vec!["^^identity('0x0000000000000000')".into()],
+            "^^c" => {
+                self.stored = None;
+                vec!["clearing user script...".into()]
+            }
+            "^^p" => match &self.stored {
+                Some(script) => script.lines().map(String::from).collect(),
+                None => vec!["no user script.".into()],
+            },
+            "^^r" => vec![BANNER.into()],
+            "^^f" => vec!["Running: First.lua".into()],
+            // The real thing reboots into its bootloader and goes quiet
+            "^^b" => vec![],
+            lua => eval(lua),
+        }
+    }
+}
+
+// Runs the mock against the far end of a serial pair until it's closed
+pub async fn serve(mut stream: SerialStream) {
+    let mut crow = MockCrow::new();
+    let mut buf = vec![0; 1024];
+
+    loop {
+        let read = match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(read) => read,
+        };
+        info!(target: "pidgeon::serial", "Mock crow got: '{}'", String::from_utf8_lossy(&buf[..read]));
+
+        for line in crow.feed(&buf[..read]) {
+            if stream
+                .write_all(format!("{line}\r\n").as_bytes())
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn find_any<'a>(haystack: &[u8], needles: &[&'a [u8]]) -> Option<(usize, &'a [u8])> {
+    needles
+        .iter()
+        .filter_map(|needle| Some((find(haystack, needle)?, *needle)))
+        .min_by_key(|(at, _)| *at)
+}
+
+// Each statement on its own, split on newlines and `;`. Only `print(...)` says anything
+fn eval(lua: &str) -> Vec<String> {
+    lua.split(['\n', ';'])
+        .filter_map(|statement| {
+            let arg = statement
+                .trim()
+                .strip_prefix("print(")?
+                .strip_suffix(')')?
+                .trim();
+            Some(value(arg).unwrap_or_else(|| "nil".into()))
+        })
+        .collect()
+}
+
+fn value(expr: &str) -> Option<String> {
+    if let Some(s) = expr
+        .strip_prefix('\'')
+        .and_then(|s| s.strip_suffix('\''))
+        .or_else(|| expr.strip_prefix('"').and_then(|s| s.strip_suffix('"')))
+    {
+        return Some(s.to_string());
+    }
+
+    match expr {
+        "collectgarbage('count')" | "collectgarbage(\"count\")" => Some(MEMORY_KB.to_string()),
+        _ if expr.starts_with("input[") && expr.ends_with("].volts") => Some("0.0".into()),
+        _ => {
+            let mut parser = Arith {
+                chars: expr.chars().filter(|c| !c.is_whitespace()).collect(),
+                at: 0,
+            };
+            let number = parser.sum()?;
+            (parser.at == parser.chars.len()).then(|| number.to_string())
+        }
+    }
+}
+
+// Lua 5.3 numbers, just enough for arithmetic: integers stay integers until a `/` or a float
+#[derive(Debug, Clone, Copy)]
+enum Number {
+    Int(i64),
+    Float(f64),
+}
+
+impl Number {
+    fn float(self) -> f64 {
+        match self {
+            Number::Int(n) => n as f64,
+            Number::Float(n) => n,
+        }
+    }
+
+    fn apply(self, op: char, rhs: Number) -> Option<Number> {
+        Some(match (self, op, rhs) {
+            (_, '/', _) => Number::Float(self.float() / rhs.float()),
+            (Number::Int(a), '+', Number::Int(b)) => Number::Int(a.checked_add(b)?),
+            (Number::Int(a), '-', Number::Int(b)) => Number::Int(a.checked_sub(b)?),
+            (Number::Int(a), '*', Number::Int(b)) => Number::Int(a.checked_mul(b)?),
+            (a, '+', b) => Number::Float(a.float() + b.float()),
+            (a, '-', b) => Number::Float(a.float() - b.float()),
+            (a, '*', b) => Number::Float(a.float() * b.float()),
+            _ => return None,
+        })
+    }
+}
+
+impl std::fmt::Display for Number {
+    // Lua prints whole floats with a trailing .0
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Number::Int(n) => write!(f, "{n}"),
+            Number::Float(n) if n.fract() == 0.0 && n.is_finite() => write!(f, "{n:.1}"),
+            Number::Float(n) => write!(f, "{n}"),
+        }
+    }
+}
+
+struct Arith {
+    chars: Vec<char>,
+    at: usize,
+}
+
+impl Arith {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.at).copied()
+    }
+
+    fn sum(&mut self) -> Option<Number> {
+        let mut lhs = self.product()?;
+        while let Some(op @ ('+' | '-')) = self.peek() {
+            self.at += 1;
+            lhs = lhs.apply(op, self.product()?)?;
+        }
+        Some(lhs)
+    }
+
+    fn product(&mut self) -> Option<Number> {
+        let mut lhs = self.atom()?;
+        while let Some(op @ ('*' | '/')) = self.peek() {
+            self.at += 1;
+            lhs = lhs.apply(op, self.atom()?)?;
+        }
+        Some(lhs)
+    }
+
+    fn atom(&mut self) -> Option<Number> {
+        match self.peek()? {
+            '(' => {
+                self.at += 1;
+                let inner = self.sum()?;
+                (self.peek()? == ')').then(|| self.at += 1)?;
+                Some(inner)
+            }
+            '-' => {
+                self.at += 1;
+                Number::Int(0).apply('-', self.atom()?)
+            }
+            _ => {
+                let start = self.at;
+                while self.peek().is_some_and(|c| c.is_ascii_digit() || c == '.') {
+                    self.at += 1;
+                }
+                let digits: String = self.chars[start..self.at].iter().collect();
+
+                if digits.contains('.') {
+                    digits.parse().ok().map(Number::Float)
+                } else {
+                    digits.parse().ok().map(Number::Int)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands() {
+        let mut crow = MockCrow::new();
+        assert_eq!(crow.feed(b"^^v\n"), ["^^version('v4.0.0-sim')"]);
+        assert_eq!(crow.feed(b"^^r\r\n"), [BANNER]);
+        assert_eq!(crow.feed(b"^^p\n"), ["no user script."]);
+        assert!(crow.feed(b"\n").is_empty());
+    }
+
+    #[test]
+    fn evaluates_prints() {
+        let mut crow = MockCrow::new();
+        assert_eq!(crow.feed(b"print(1+1)\n"), ["2"]);
+        assert_eq!(crow.feed(b"print((1 + 2) * -3)\n"), ["-9"]);
+        assert_eq!(crow.feed(b"print(3/2); print(2 * 1.0)\n"), ["1.5", "2.0"]);
+        assert_eq!(crow.feed(b"print('hi')\n"), ["hi"]);
+        assert_eq!(crow.feed(b"print(x)\n"), ["nil"]);
+        // A statement on its own says nothing
+        assert!(crow.feed(b"x = 1\n").is_empty());
+    }
+
+    // Input can arrive in any pieces, nothing is answered until a whole command's in
+    #[test]
+    fn split_input() {
+        let mut crow = MockCrow::new();
+        assert!(crow.feed(b"print(").is_empty());
+        assert_eq!(crow.feed(b"'a')\nprint('b')\n"), ["a", "b"]);
+    }
+
+    #[test]
+    fn fenced_chunk() {
+        let mut crow = MockCrow::new();
+        assert!(crow.feed(b"```print('one')\n").is_empty());
+        assert_eq!(crow.feed(b"print('two')```\n"), ["one", "two"]);
+    }
+
+    #[test]
+    fn uploads() {
+        let mut crow = MockCrow::new();
+        assert_eq!(crow.feed(b"^^sx = 1^^e\n"), ["Running: user script"]);
+        assert_eq!(crow.feed(b"^^p\n"), ["no user script."]);
+
+        assert!(crow.feed(b"^^sx = 2\n").is_empty());
+        assert_eq!(
+            crow.feed(b"y = 3^^w\n"),
+            ["User script updated.", "Running: user script"]
+        );
+        assert_eq!(crow.feed(b"^^p\n"), ["x = 2", "y = 3"]);
+
+        assert_eq!(crow.feed(b"^^c\n"), ["clearing user script..."]);
+        assert_eq!(crow.feed(b"^^p\n"), ["no user script."]);
+    }
+}
@@ -0,0 +1,100 @@
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::io::IsTerminal;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Format {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorChoice {
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    // Auto colors only when stdout is a terminal and NO_COLOR isn't set
+    pub fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Auto => {
+                std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+            }
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+const BOLD_GREEN: &str = "\x1b[1;32m";
+const DIM: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+// Left-aligned columns sized to their widest cell, with highlighted rows drawn in color
+pub struct Table {
+    headers: Vec<&'static str>,
+    rows: Vec<(Vec<String>, bool)>,
+}
+
+impl Table {
+    pub fn new(headers: &[&'static str]) -> Self {
+        Self {
+            headers: headers.to_vec(),
+            rows: Vec::new(),
+        }
+    }
+
+    pub fn row(&mut self, cells: Vec<String>, highlight: bool) {
+        debug_assert_eq!(cells.len(), self.headers.len());
+        self.rows.push((cells, highlight));
+    }
+
+    pub fn render(&self, color: bool) -> String {
+        let widths = self
+            .headers
+            .iter()
+            .enumerate()
+            .map(|(col, header)| {
+                self.rows
+                    .iter()
+                    .map(|(cells, _)| cells[col].chars().count())
+                    .fold(header.len(), usize::max)
+            })
+            .collect::<Vec<_>>();
+
+        let line = |cells: &mut dyn Iterator<Item = &str>| {
+            cells
+                .zip(&widths)
+                .map(|(cell, width)| format!("{cell:<width$}"))
+                .collect::<Vec<_>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        };
+
+        let mut out = String::new();
+
+        let header = line(&mut self.headers.iter().copied());
+        if color {
+            out.push_str(&format!("{DIM}{header}{RESET}\n"));
+        } else {
+            out.push_str(&format!("{header}\n"));
+        }
+
+        for (cells, highlight) in &self.rows {
+            let row = line(&mut cells.iter().map(String::as_str));
+            if color && *highlight {
+                out.push_str(&format!("{BOLD_GREEN}{row}{RESET}\n"));
+            } else {
+                out.push_str(&format!("{row}\n"));
+            }
+        }
+
+        out
+    }
+}
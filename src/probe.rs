@@ -0,0 +1,81 @@
+use super::{crow::Crow, error::*};
+use std::time::Duration;
+use tracing::info;
+
+const START: usize = 1024;
+// Sizes closer together than this aren't worth another upload
+const RESOLUTION: usize = 256;
+const MARKER_TIMEOUT: Duration = Duration::from_secs(2);
+
+// A script of exactly `size` bytes: comment padding followed by a print of a marker we can wait for
+fn generate(size: usize) -> (String, String) {
+    let marker = format!("pidgeon-probe-{size}");
+    let tail = format!("print('{marker}')\n");
+
+    let line = format!("--{}\n", "-".repeat(61));
+    let mut script = String::with_capacity(size);
+    while script.len() + line.len() + tail.len() <= size {
+        script.push_str(&line);
+    }
+    let remaining = size.saturating_sub(script.len() + tail.len());
+    if remaining >= 3 {
+        script.push_str("--");
+        script.push_str(&"-".repeat(remaining - 3));
+        script.push('\n');
+    } else {
+        script.push_str(&"\n".repeat(remaining));
+    }
+    script.push_str(&tail);
+
+    (script, marker)
+}
+
+async fn uploads_cleanly(crow: &mut Crow, size: usize) -> Result<bool> {
+    let (script, marker) = generate(size);
+    crow.write_script(&script).await?;
+
+    let wait = async {
+        loop {
+            let line = crow.read_line().await?;
+            if line.trim_end() == marker {
+                break Ok(true);
+            }
+            info!(target: "pidgeon::serial", "probe {size}: crow said '{}'", line.trim_end());
+        }
+    };
+
+    match tokio::time::timeout(MARKER_TIMEOUT, wait).await {
+        Ok(res) => res,
+        Err(_) => Ok(false),
+    }
+}
+
+// Double the script size until an upload fails, then bisect between the last good and first bad
+// sizes. Returns the largest size that uploaded and ran, or None if even the smallest failed
+pub async fn probe_limits(crow: &mut Crow, max: usize) -> Result<Option<usize>> {
+    let mut good = None;
+    let mut bad = None;
+
+    let mut size = START.min(max);
+    loop {
+        let ok = uploads_cleanly(crow, size).await?;
+        println!("{size:>8} bytes: {}", if ok { "ok" } else { "FAILED" });
+
+        if ok {
+            good = Some(size);
+        } else {
+            bad = Some(size);
+        }
+
+        size = match (good, bad) {
+            (Some(good), None) if good >= max => break,
+            (Some(good), None) => (good * 2).min(max),
+            (None, Some(_)) => break,
+            (Some(good), Some(bad)) if bad - good <= RESOLUTION => break,
+            (Some(good), Some(bad)) => good + (bad - good) / 2,
+            (None, None) => unreachable!(),
+        };
+    }
+
+    Ok(good)
+}
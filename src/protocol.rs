@@ -0,0 +1,494 @@
+use super::error::*;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::info;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum Message {
+    Success {
+        request_id: u64,
+        contents: String,
+    },
+    // Follows the last Success or Failure for a request, nothing more is coming for it
+    Done {
+        request_id: u64,
+    },
+    // Stop waiting on a request and answer it Failure "cancelled". `clear` also sends crow ^^c,
+    // for a script that's run away with it
+    Cancel {
+        request_id: u64,
+        #[serde(default)]
+        clear: bool,
+    },
+    // A line crow printed on its own, not in reply to anything. Goes to every session
+    Event {
+        contents: String,
+    },
+    Check {
+        #[serde(default)]
+        version: Option<u32>,
+    },
+    // Answered Affirm once the session is open, or a Failure saying why it isn't
+    Start {
+        #[serde(default)]
+        version: Option<u32>,
+    },
+    // First thing a client sends to a server started with --auth-token
+    Auth {
+        token: String,
+    },
+    // Ends a session, freeing the crow for the next client
+    Stop,
+    Affirm,
+    // Answered with a Pong straight away, so either end can tell a live link from a dead one
+    Ping,
+    Pong,
+    Failure {
+        request_id: Option<u64>,
+        contents: String,
+    },
+}
+use Message::*;
+
+// Bumped whenever a change would confuse the other end. Clients send theirs with Check and Start,
+// one that leaves it out predates versioning and is taken to speak v1
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// Largest frame either end will take, unless the server is told otherwise
+pub const BUFSIZE: usize = 512 * 512;
+
+// Length-prefixed framing: a big-endian u32 length followed by that many bytes of JSON
+
+// How much more room the read buffer makes each time it fills up
+const READ_CHUNK: usize = 4096;
+
+// Bytes pile up in `buf` and a frame only comes off once it's all there, so a read dropped halfway,
+// e.g. by losing a select, loses nothing. `buf` can hold the start of the next frame, so it's kept
+// for as long as the connection. A prefix over `max` is refused before its body is read, the
+// stream can't be trusted after
+pub async fn read_frame<R>(reader: &mut R, buf: &mut Vec<u8>, max: usize) -> Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    loop {
+        if let Some(prefix) = buf.first_chunk::<4>() {
+            let len = u32::from_be_bytes(*prefix) as usize;
+            if len > max {
+                return Err(Error::MessageTooLarge { len, max });
+            }
+            if buf.len() >= 4 + len {
+                info!(target: "pidgeon::server", "read frame of {len} bytes");
+                let frame = buf[4..4 + len].to_vec();
+                buf.drain(..4 + len);
+                return Ok(frame);
+            }
+        }
+
+        buf.reserve(READ_CHUNK);
+        if reader.read_buf(buf).await? == 0 {
+            // Between frames that's the other end hanging up, partway through one it isn't
+            return Err(if buf.is_empty() {
+                Error::ConnectionClosed
+            } else {
+                std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()
+            });
+        }
+    }
+}
+
+pub async fn write_frame<W>(writer: &mut W, chunk: &[u8]) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let len = chunk.len();
+    let prefix = u32::try_from(len).map_err(|_| Error::MessageTooLarge {
+        len,
+        max: u32::MAX as usize,
+    })?;
+
+    writer.write_u32(prefix).await?;
+    info!(target: "pidgeon::server", "wrote prefix {len}");
+    writer.write_all(chunk).await?;
+    info!(target: "pidgeon::server", "wrote {len} byte successfully");
+
+    Ok(())
+}
+
+pub async fn read_message<R>(reader: &mut R, buf: &mut Vec<u8>) -> Result<Message>
+where
+    R: AsyncRead + Unpin,
+{
+    let frame = read_frame(reader, buf, BUFSIZE).await?;
+    Ok(serde_json::from_slice(&frame)?)
+}
+
+pub async fn write_message<W>(writer: &mut W, msg: &Message) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let bytes = serde_json::to_vec(msg)?;
+    write_frame(writer, &bytes).await
+}
+
+// What a `Check` against a pidgeon server found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckStatus {
+    // The server answered `Affirm`, a session can be started
+    Available,
+    // Another client holds the crow
+    Busy,
+    // The server wants an auth token, and this client's was missing or wrong
+    Unauthorized,
+    // The server speaks another version of the protocol, the one it named if it did
+    Incompatible { speaks: Option<u32> },
+    // The server couldn't be reached or didn't answer in time
+    Unreachable,
+}
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub struct Client {
+    addr: SocketAddr,
+    timeout: Duration,
+    token: Option<String>,
+    session: Option<TcpStream>,
+    buf: Vec<u8>,
+}
+
+impl Client {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            timeout: DEFAULT_TIMEOUT,
+            token: None,
+            session: None,
+            buf: Vec::new(),
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    // Authenticate every connection, for a server started with --auth-token
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    async fn connect(&self) -> Result<TcpStream> {
+        let mut conn = TcpStream::connect(self.addr).await?;
+        if let Some(token) = &self.token {
+            let token = token.clone();
+            write_message(&mut conn, &Auth { token }).await?;
+        }
+
+        Ok(conn)
+    }
+
+    // Ask the server whether it's free, on a throwaway connection so an open session isn't disturbed
+    pub async fn check(&self) -> CheckStatus {
+        let ask = async {
+            let mut conn = self.connect().await?;
+            let version = Some(PROTOCOL_VERSION);
+            write_message(&mut conn, &Check { version }).await?;
+
+            read_message(&mut conn, &mut Vec::new()).await
+        };
+
+        match tokio::time::timeout(self.timeout, ask).await {
+            Ok(Ok(Affirm)) => CheckStatus::Available,
+            Ok(Ok(Failure { contents, .. })) if contents == "BUSY" => CheckStatus::Busy,
+            Ok(Ok(Failure { contents, .. })) if contents == "unauthorized" => {
+                CheckStatus::Unauthorized
+            }
+            // e.g. "protocol v2 isn't supported, this server speaks v1"
+            Ok(Ok(Failure { contents, .. })) if contents.contains(" isn't supported") => {
+                let speaks = contents
+                    .rsplit_once("speaks v")
+                    .and_then(|(_, version)| version.parse().ok());
+                CheckStatus::Incompatible { speaks }
+            }
+            Ok(Ok(other)) => {
+                info!(target: "pidgeon::server", "Unexpected reply to Check: {other:?}");
+                CheckStatus::Unreachable
+            }
+            Ok(Err(err)) => {
+                info!(target: "pidgeon::server", "Check failed: {err}");
+                CheckStatus::Unreachable
+            }
+            Err(_) => {
+                info!(target: "pidgeon::server", "Check timed out after {:?}", self.timeout);
+                CheckStatus::Unreachable
+            }
+        }
+    }
+
+    // Open a persistent session with the server, once it's answered `Affirm`
+    pub async fn start(&mut self) -> Result<()> {
+        let mut conn = self.connect().await?;
+        let version = Some(PROTOCOL_VERSION);
+        write_message(&mut conn, &Start { version }).await?;
+
+        match tokio::time::timeout(self.timeout, read_message(&mut conn, &mut self.buf)).await {
+            Ok(Ok(Affirm)) => {}
+            Ok(Ok(Failure { contents, .. })) => return Err(Error::SessionRefused(contents)),
+            Ok(Ok(other)) => return Err(Error::SessionRefused(format!("answered {other:?}"))),
+            Ok(Err(err)) => return Err(err),
+            Err(_) => {
+                return Err(Error::SessionRefused(format!(
+                    "no answer within {:?}",
+                    self.timeout
+                )));
+            }
+        }
+        self.session = Some(conn);
+
+        Ok(())
+    }
+
+    pub async fn send(&mut self, msg: &Message) -> Result<()> {
+        let conn = self.session.as_mut().ok_or(Error::ConnectionClosed)?;
+        write_message(conn, msg).await
+    }
+
+    // The server's heartbeat Pings are answered here rather than handed back
+    pub async fn recv(&mut self) -> Result<Message> {
+        let conn = self.session.as_mut().ok_or(Error::ConnectionClosed)?;
+        loop {
+            match read_message(conn, &mut self.buf).await? {
+                Ping => write_message(conn, &Pong).await?,
+                msg => return Ok(msg),
+            }
+        }
+    }
+
+    // Check the session is still alive, only meant for while no replies are outstanding
+    pub async fn ping(&mut self) -> Result<()> {
+        self.send(&Ping).await?;
+
+        match tokio::time::timeout(self.timeout, self.recv()).await {
+            Ok(Ok(Pong)) => Ok(()),
+            Ok(Ok(_)) | Err(_) => Err(Error::ConnectionClosed),
+            Ok(Err(err)) => Err(err),
+        }
+    }
+
+    // End the session so the server frees the crow
+    pub async fn stop(&mut self) -> Result<()> {
+        self.send(&Stop).await?;
+        self.session = None;
+
+        Ok(())
+    }
+
+    pub fn disconnect(&mut self) {
+        self.session = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    fn framed(msg: &Message) -> Vec<u8> {
+        let body = serde_json::to_vec(msg).unwrap();
+        let mut bytes = (body.len() as u32).to_be_bytes().to_vec();
+        bytes.extend(body);
+        bytes
+    }
+
+    fn request() -> Message {
+        Success {
+            request_id: 7,
+            contents: "print('hi')".into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let (mut client, mut server) = duplex(BUFSIZE);
+        write_message(&mut client, &request()).await.unwrap();
+        write_message(&mut client, &Ping).await.unwrap();
+
+        let mut buf = Vec::new();
+        let msg = read_message(&mut server, &mut buf).await.unwrap();
+        assert!(matches!(msg, Success { request_id: 7, contents } if contents == "print('hi')"));
+        assert!(matches!(
+            read_message(&mut server, &mut buf).await.unwrap(),
+            Ping
+        ));
+    }
+
+    #[tokio::test]
+    async fn byte_by_byte() {
+        // Room for a single byte, so every read comes back short
+        let (mut client, mut server) = duplex(1);
+        let bytes = [framed(&request()), framed(&Pong)].concat();
+        let writer = tokio::spawn(async move {
+            for byte in bytes {
+                client.write_all(&[byte]).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+
+        let mut buf = Vec::new();
+        assert!(matches!(
+            read_message(&mut server, &mut buf).await.unwrap(),
+            Success { .. }
+        ));
+        assert!(matches!(
+            read_message(&mut server, &mut buf).await.unwrap(),
+            Pong
+        ));
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancelled_partway() {
+        let (mut client, mut server) = duplex(BUFSIZE);
+        let bytes = framed(&request());
+        let (head, tail) = bytes.split_at(bytes.len() / 2);
+        client.write_all(head).await.unwrap();
+
+        // Dropped with half a frame read, which has to still be there for the next call
+        let mut buf = Vec::new();
+        let read = read_frame(&mut server, &mut buf, BUFSIZE);
+        let timed_out = tokio::time::timeout(Duration::from_millis(20), read).await;
+        assert!(timed_out.is_err());
+
+        client.write_all(tail).await.unwrap();
+        let frame = read_frame(&mut server, &mut buf, BUFSIZE).await.unwrap();
+        assert_eq!(frame, bytes[4..]);
+        assert!(buf.is_empty());
+    }
+
+    #[tokio::test]
+    async fn closed_between_frames() {
+        let (mut client, mut server) = duplex(BUFSIZE);
+        write_message(&mut client, &Ping).await.unwrap();
+        drop(client);
+
+        let mut buf = Vec::new();
+        assert!(matches!(
+            read_message(&mut server, &mut buf).await.unwrap(),
+            Ping
+        ));
+        let err = read_message(&mut server, &mut buf).await.unwrap_err();
+        assert!(matches!(err, Error::ConnectionClosed));
+    }
+
+    #[tokio::test]
+    async fn truncated() {
+        let (mut client, mut server) = duplex(BUFSIZE);
+        let bytes = framed(&request());
+        client.write_all(&bytes[..bytes.len() - 1]).await.unwrap();
+        drop(client);
+
+        let err = read_frame(&mut server, &mut Vec::new(), BUFSIZE)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, Error::IO(ref err) if err.kind() == std::io::ErrorKind::UnexpectedEof),
+            "{err:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn truncated_prefix() {
+        let (mut client, mut server) = duplex(BUFSIZE);
+        client.write_all(&[0, 0]).await.unwrap();
+        drop(client);
+
+        let err = read_frame(&mut server, &mut Vec::new(), BUFSIZE)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::IO(_)), "{err:?}");
+    }
+
+    #[tokio::test]
+    async fn oversized() {
+        let (mut client, mut server) = duplex(BUFSIZE);
+        client.write_u32(u32::MAX).await.unwrap();
+
+        // Refused on the prefix alone, nothing's waited on or allocated for the body
+        let mut buf = Vec::new();
+        let err = read_frame(&mut server, &mut buf, 16).await.unwrap_err();
+        assert!(matches!(
+            err,
+            Error::MessageTooLarge {
+                len,
+                max: 16
+            } if len == u32::MAX as usize
+        ));
+        assert!(buf.capacity() < 1 << 20);
+    }
+
+    #[tokio::test]
+    async fn at_the_limit() {
+        let (mut client, mut server) = duplex(BUFSIZE);
+        write_frame(&mut client, &[b'x'; 16]).await.unwrap();
+        write_frame(&mut client, &[b'x'; 17]).await.unwrap();
+
+        let mut buf = Vec::new();
+        assert_eq!(
+            read_frame(&mut server, &mut buf, 16).await.unwrap().len(),
+            16
+        );
+        let err = read_frame(&mut server, &mut buf, 16).await.unwrap_err();
+        assert!(matches!(err, Error::MessageTooLarge { len: 17, max: 16 }));
+    }
+
+    // A server that answers a single Check with `reply`
+    async fn answering(reply: Message) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            read_message(&mut conn, &mut Vec::new()).await.unwrap();
+            write_message(&mut conn, &reply).await.unwrap();
+        });
+
+        addr
+    }
+
+    fn failure(contents: &str) -> Message {
+        Failure {
+            request_id: None,
+            contents: contents.into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn check_statuses() {
+        let mismatch = "protocol v2 isn't supported, this server speaks v1";
+        for (reply, want) in [
+            (Affirm, CheckStatus::Available),
+            (failure("BUSY"), CheckStatus::Busy),
+            (failure("unauthorized"), CheckStatus::Unauthorized),
+            (
+                failure(mismatch),
+                CheckStatus::Incompatible { speaks: Some(1) },
+            ),
+            (failure("don't understand"), CheckStatus::Unreachable),
+        ] {
+            let addr = answering(reply.clone()).await;
+            assert_eq!(Client::new(addr).check().await, want, "{reply:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn check_without_a_server() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        assert_eq!(Client::new(addr).check().await, CheckStatus::Unreachable);
+    }
+}
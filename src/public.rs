@@ -0,0 +1,145 @@
+use super::{
+    crow::{self, Crow, ReadTimeouts},
+    error::*,
+};
+use serde::Serialize;
+
+// A parameter a script declared with `public.add`, as reported by `public.discover()`
+#[derive(Debug, Clone, Serialize)]
+pub struct PublicParam {
+    pub name: String,
+    pub kind: String,
+    pub value: String,
+}
+
+// Split on commas that aren't inside quotes or a table
+fn split_args(args: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut depth, mut quote, mut start) = (0, None, 0);
+
+    for (i, c) in args.char_indices() {
+        match (c, quote) {
+            ('"' | '\'', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            (_, Some(_)) => {}
+            ('{', None) => depth += 1,
+            ('}', None) => depth -= 1,
+            (',', None) if depth == 0 => {
+                parts.push(args[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(args[start..].trim());
+
+    parts
+}
+
+fn unquote(s: &str) -> &str {
+    s.strip_prefix(['"', '\''])
+        .and_then(|s| s.strip_suffix(['"', '\'']))
+        .unwrap_or(s)
+}
+
+// Discovery prints one `^^pub(name,value,{type,...})` line per parameter. The type table may be
+// missing, in which case the kind is guessed from the value
+pub fn parse(line: &str) -> Option<PublicParam> {
+    let args = line.trim().strip_prefix("^^pub(")?.strip_suffix(')')?;
+
+    match split_args(args).as_slice() {
+        [name, value, rest @ ..] => {
+            let kind = rest
+                .first()
+                .and_then(|spec| spec.strip_prefix('{'))
+                .and_then(|spec| split_args(spec.trim_end_matches('}')).first().copied())
+                .map(|kind| unquote(kind).to_string())
+                .filter(|kind| !kind.is_empty())
+                .unwrap_or_else(|| {
+                    if value.parse::<f64>().is_ok() {
+                        "number".into()
+                    } else if value.starts_with('{') {
+                        "table".into()
+                    } else {
+                        "string".into()
+                    }
+                });
+
+            Some(PublicParam {
+                name: unquote(name).to_string(),
+                kind,
+                value: unquote(value).to_string(),
+            })
+        }
+        _ => None,
+    }
+}
+
+pub async fn discover(crow: &mut Crow, timeouts: ReadTimeouts) -> Result<Vec<PublicParam>> {
+    crow.write_all("public.discover()").await?;
+    let response = crow::read_response(crow, timeouts, |_| {}).await?;
+
+    Ok(response
+        .lines
+        .iter()
+        .filter_map(|line| parse(line))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(line: &str) -> Option<(String, String, String)> {
+        parse(line).map(|param| (param.name, param.kind, param.value))
+    }
+
+    fn expected(name: &str, kind: &str, value: &str) -> Option<(String, String, String)> {
+        Some((name.into(), kind.into(), value.into()))
+    }
+
+    #[test]
+    fn quoted_commas() {
+        assert_eq!(split_args("'a,b', {1,2}, c"), ["'a,b'", "{1,2}", "c"]);
+        assert_eq!(
+            fields("^^pub(\"a,b\",'x, y',{\"string\"})\r\n"),
+            expected("a,b", "string", "x, y")
+        );
+    }
+
+    #[test]
+    fn nested_tables() {
+        assert_eq!(
+            fields("^^pub(seq,{1,{2,3}},{\"table\",{1,2}})"),
+            expected("seq", "table", "{1,{2,3}}")
+        );
+        assert_eq!(
+            fields("^^pub(rate,0.5,{'number',0,1})"),
+            expected("rate", "number", "0.5")
+        );
+    }
+
+    // Without a type table the kind comes from the value
+    #[test]
+    fn guessed_kinds() {
+        assert_eq!(fields("^^pub(rate,0.5)"), expected("rate", "number", "0.5"));
+        assert_eq!(
+            fields("^^pub(seq,{1,2})"),
+            expected("seq", "table", "{1,2}")
+        );
+        assert_eq!(fields("^^pub(name,'hi')"), expected("name", "string", "hi"));
+    }
+
+    #[test]
+    fn malformed_lines() {
+        for line in [
+            "^^pub(rate)",
+            "^^pub(rate,0.5",
+            "pub(rate,0.5)",
+            "^^stream(1,0.25)",
+            "",
+        ] {
+            assert_eq!(fields(line), None, "{line:?}");
+        }
+    }
+}
@@ -1,22 +1,644 @@
+use clap::Args;
+use rustyline::{
+    Context, Editor, ExternalPrinter, Helper,
+    completion::{Completer, Pair},
+    error::ReadlineError,
+    highlight::{CmdKind, Highlighter},
+    hint::Hinter,
+    history::DefaultHistory,
+    validate::Validator,
+};
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::{Notify, mpsc};
 use tracing::info;
 
-use super::{crow::CrowWriter, error::*};
+use super::{
+    complete,
+    crow::{Crow, CrowWriter, ReadTimeouts, ReconnectPolicy},
+    error::*,
+    macros,
+};
 
-pub async fn run(mut writer: CrowWriter) -> Result<()> {
-    let mut rl = rustyline::DefaultEditor::new()?;
+// Lua run around every command, e.g. `--postamble "print(collectgarbage('count'))"`
+#[derive(Debug, Clone, Default, Args)]
+pub struct Wrap {
+    /// Lua to run before every command
+    #[arg(long)]
+    pub preamble: Option<String>,
+    /// Lua to run after every command
+    #[arg(long)]
+    pub postamble: Option<String>,
+}
+
+impl Wrap {
+    // Joined onto one line so the framing decision is made on the combined length
+    pub fn apply(&self, line: &str) -> String {
+        [
+            self.preamble.as_deref(),
+            Some(line),
+            self.postamble.as_deref(),
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join("; ")
+    }
+}
+
+const KEYWORD: &str = "\x1b[34m";
+const STRING: &str = "\x1b[32m";
+const NUMBER: &str = "\x1b[35m";
+const COMMENT: &str = "\x1b[90m";
+const BRACKET: &str = "\x1b[1;33m";
+const RESET: &str = "\x1b[0m";
+
+const KEYWORDS: &[&str] = &[
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if", "in",
+    "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
+// Completes crow's API on Tab and colors the Lua as it's typed. What's sent is the line as typed
+struct LuaHelper {
+    color: bool,
+    // Off for the final redraw once a line's entered, so its brackets aren't left lit
+    brackets: Cell<bool>,
+}
+
+impl Completer for LuaHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        Ok(complete::complete(line, pos))
+    }
+}
+
+impl Highlighter for LuaHelper {
+    fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
+        if !self.color {
+            return Cow::Borrowed(line);
+        }
+
+        let partner = self
+            .brackets
+            .get()
+            .then(|| matching_bracket(line, pos))
+            .flatten();
+        Cow::Owned(highlight(line, partner))
+    }
+
+    fn highlight_char(&self, _: &str, _: usize, kind: CmdKind) -> bool {
+        self.brackets.set(kind != CmdKind::ForcedRefresh);
+        self.color
+    }
+}
+
+impl Hinter for LuaHelper {
+    type Hint = String;
+}
+
+impl Validator for LuaHelper {}
+
+impl Helper for LuaHelper {}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub wrap: Wrap,
+    // Where the Lua sent during the session is written on exit
+    pub save_on_exit: Option<PathBuf>,
+    pub history_file: Option<PathBuf>,
+    // Highlight the Lua as it's typed
+    pub color: bool,
+    pub timeouts: ReadTimeouts,
+    pub reconnect: ReconnectPolicy,
+}
+
+pub async fn run(crow: Crow, opts: &Options) -> Result<()> {
+    let Options {
+        wrap,
+        save_on_exit,
+        history_file,
+        color,
+        timeouts,
+        reconnect,
+    } = opts;
+    let (color, timeouts, reconnect) = (*color, *timeouts, *reconnect);
+    let mut rl = Editor::<LuaHelper, DefaultHistory>::new().map_err(Error::NoTerminal)?;
+    rl.set_helper(Some(LuaHelper {
+        color,
+        brackets: Cell::new(false),
+    }));
+
+    // Nothing to load on a first run
+    if let Some(path) = history_file
+        && path.exists()
+        && let Err(err) = rl.load_history(path)
+    {
+        println!("couldn't load history from {}: {err}", path.display());
+    }
+
+    // Crow output is drawn above the prompt instead of through it, when the terminal can
+    let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+    let heard = Arc::new(Notify::new());
+    let mut printer = rl.create_external_printer().ok();
+    let printer_handle = tokio::spawn({
+        let heard = heard.clone();
+        async move {
+            while let Some(line) = rx.recv().await {
+                match printer.as_mut() {
+                    Some(printer) => {
+                        let _ = printer.print(line);
+                    }
+                    None => println!("{line}"),
+                }
+                heard.notify_waiters();
+            }
+        }
+    });
+
+    let (reader, mut writer) = crow.split();
+    let mut reader_handle = tokio::spawn(reader.run_to(tx.clone(), None));
+    let mut history = Vec::new();
+
+    let session = async {
+        // Lua typed so far that doesn't make a whole statement yet
+        let mut block = String::new();
+
+        loop {
+            let prompt = if block.is_empty() { ">> " } else { ".. " };
+            let line = match rl.readline(prompt) {
+                Ok(line) => line,
+                // Ctrl-C throws away the line, and any block it was part of, rather than the session
+                Err(ReadlineError::Interrupted) => {
+                    block.clear();
+                    continue;
+                }
+                // Ctrl-D ends it like "exit"
+                Err(ReadlineError::Eof) => break Ok(()),
+                Err(err) => break Err(err.into()),
+            };
+            info!(target: "pidgeon::repl", "Got line: {line}");
+
+            // Listening before anything's sent, a quick reply could beat us to it otherwise
+            let replied = heard.notified();
+            tokio::pin!(replied);
+            replied.as_mut().enable();
+
+            if block.is_empty() && line.as_str() == "exit" {
+                let _ = rl.add_history_entry(line.as_str());
+                break Ok(());
+            }
+
+            // Lines starting with '.' or ':' are repl commands rather than Lua
+            let meta = line
+                .strip_prefix(['.', ':'])
+                .filter(|_| block.is_empty())
+                .map(|meta| {
+                    meta.split_once(char::is_whitespace)
+                        .map(|(command, arg)| (command, arg.trim()))
+                        .unwrap_or((meta, ""))
+                });
+            if meta.is_some() {
+                let _ = rl.add_history_entry(line.as_str());
+            }
+
+            let line = match meta {
+                // Held until it's balanced, a trailing backslash or a blank line sends it as is
+                None => {
+                    let (line, forced) = match line.strip_suffix('\\') {
+                        Some(line) => (line, true),
+                        None => (line.as_str(), !block.is_empty() && line.trim().is_empty()),
+                    };
+                    if !block.is_empty() {
+                        block.push('\n');
+                    }
+                    block.push_str(line);
+
+                    if !forced && unfinished(&block) {
+                        continue;
+                    }
+                    let lua = block.trim_end().to_string();
+                    block.clear();
+                    let _ = rl.add_history_entry(lua.as_str());
+                    lua
+                }
+                // A file's Lua is run in the current script, anything else is taken as a macro
+                Some(("run", arg)) if Path::new(arg).is_file() => {
+                    match std::fs::read_to_string(arg) {
+                        Ok(lua) => lua.trim_end().to_string(),
+                        Err(err) => {
+                            println!("Couldn't read {arg}: {err}");
+                            continue;
+                        }
+                    }
+                }
+                Some(("run", alias)) => match macros::lookup(alias) {
+                    Ok(snippet) => snippet,
+                    Err(err) => {
+                        println!("{err}");
+                        continue;
+                    }
+                },
+                // Replaces the running script, like `pidgeon file`. Crow's "Running: ..." is the
+                // confirmation
+                Some(("load", path)) => {
+                    match std::fs::read_to_string(path) {
+                        Ok(script) => {
+                            writer.write_script(&script).await?;
+                            wait_reply(&heard, replied, timeouts).await;
+                        }
+                        Err(err) => println!("Couldn't read {path}: {err}"),
+                    }
+                    continue;
+                }
+                Some(("panic", _)) => {
+                    writer.panic().await?;
+                    continue;
+                }
+                // The boot banner shows up through the reader like any other output
+                Some(("restart", _)) => {
+                    writer.restart().await?;
+                    continue;
+                }
+                Some((signal @ ("dtr" | "rts"), level)) => {
+                    let level = match level {
+                        "on" => true,
+                        "off" => false,
+                        _ => {
+                            println!("Usage: .{signal} on|off");
+                            continue;
+                        }
+                    };
+                    let res = match signal {
+                        "dtr" => writer.set_dtr(level),
+                        _ => writer.set_rts(level),
+                    };
+                    if let Err(err) = res {
+                        println!("{err}");
+                    }
+                    continue;
+                }
+                Some((command, _)) => {
+                    println!(
+                        "Unknown command '.{command}', available: .run <alias|path>, .load <path>, \
+                         .panic, .restart, .dtr on|off, .rts on|off"
+                    );
+                    continue;
+                }
+            };
+
+            let command = wrap.apply(&line);
+            match send(&mut writer, &command).await {
+                Err(err) if err.is_disconnect() => {
+                    println!("Lost the crow ({err}), reconnecting");
+                    reader_handle.abort();
+
+                    let reader = writer.reconnect(reconnect).await?;
+                    reader_handle = tokio::spawn(reader.run_to(tx.clone(), None));
+                    println!("Reconnected");
+
+                    send(&mut writer, &command).await?;
+                }
+                res => res?,
+            }
+            // Only Lua that was typed in, a .run sends Lua too but is saved as neither the command
+            // nor what it expanded to
+            if meta.is_none() {
+                history.push(line);
+            }
+
+            wait_reply(&heard, replied, timeouts).await;
+        }
+    };
+    let res = session.await;
+    reader_handle.abort();
+    printer_handle.abort();
+    // Gone for good before returning, so the port is free to be opened again, e.g. for a teardown
+    let _ = reader_handle.await;
+
+    // Both saved however the session ended, so a Ctrl-D doesn't lose the work
+    if let Some(path) = history_file {
+        let saved = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => std::fs::create_dir_all(dir),
+            _ => Ok(()),
+        }
+        .map_err(ReadlineError::from)
+        .and_then(|()| rl.save_history(path));
+        if let Err(err) = saved {
+            println!("couldn't save history to {}: {err}", path.display());
+        }
+    }
+
+    if let Some(path) = save_on_exit {
+        let mut script = history.join("\n");
+        script.push('\n');
+        std::fs::write(path, script)?;
+        println!("saved {} command(s) to {}", history.len(), path.display());
+    }
+
+    res
+}
+
+// The prompt waits on the reply, so it's printed straight under the input rather than around a
+// prompt that's already been drawn. A statement that prints nothing only holds it up for the idle
+// timeout
+async fn wait_reply(heard: &Notify, replied: impl Future<Output = ()>, timeouts: ReadTimeouts) {
+    let _ = tokio::time::timeout(timeouts.response, async {
+        if tokio::time::timeout(timeouts.idle, replied).await.is_ok() {
+            while tokio::time::timeout(timeouts.idle, heard.notified())
+                .await
+                .is_ok()
+            {}
+        }
+    })
+    .await;
+}
+
+// Crow runs each line it's sent on its own, so a block has to arrive fenced to run as one
+async fn send(writer: &mut CrowWriter, command: &str) -> Result<()> {
+    if command.contains('\n') {
+        writer.write_delimited(command).await
+    } else {
+        writer.write_line(command).await
+    }
+}
+
+// Whether there's more to come: a block, bracket, long string or long comment left open. Strings
+// and comments are skipped so a keyword inside one isn't counted. Anything malformed is left for
+// crow to complain about
+fn unfinished(lua: &str) -> bool {
+    let bytes = lua.as_bytes();
+    let mut depth = 0i32;
+    let mut at = 0;
+
+    while at < bytes.len() {
+        let rest = &bytes[at..];
+        match rest[0] {
+            b'-' if rest.starts_with(b"--") => {
+                at += 2;
+                match long_bracket(&bytes[at..]) {
+                    Some(level) => match close_long(&bytes[at..], level) {
+                        Some(len) => at += len,
+                        None => return true,
+                    },
+                    None => {
+                        at += bytes[at..]
+                            .iter()
+                            .position(|&b| b == b'\n')
+                            .unwrap_or(bytes.len() - at)
+                    }
+                }
+            }
+            b'[' if let Some(level) = long_bracket(rest) => match close_long(rest, level) {
+                Some(len) => at += len,
+                None => return true,
+            },
+            quote @ (b'\'' | b'"') => {
+                at += 1;
+                loop {
+                    match bytes.get(at) {
+                        None | Some(b'\n') => return false,
+                        Some(b'\\') => at += 2,
+                        Some(&b) if b == quote => break at += 1,
+                        Some(_) => at += 1,
+                    }
+                }
+            }
+            b'(' | b'[' | b'{' => {
+                depth += 1;
+                at += 1
+            }
+            b')' | b']' | b'}' => {
+                depth -= 1;
+                at += 1
+            }
+            b if b.is_ascii_alphabetic() || b == b'_' => {
+                let start = at;
+                while bytes
+                    .get(at)
+                    .is_some_and(|&b| b.is_ascii_alphanumeric() || b == b'_')
+                {
+                    at += 1;
+                }
+                match &lua[start..at] {
+                    "function" | "do" | "if" | "repeat" => depth += 1,
+                    "end" | "until" => depth -= 1,
+                    _ => {}
+                }
+            }
+            _ => at += 1,
+        }
+    }
+
+    depth > 0
+}
+
+// The level of a long bracket opening `bytes`, i.e. how many `=` are in `[==[`
+fn long_bracket(bytes: &[u8]) -> Option<usize> {
+    let level = bytes.get(1..)?.iter().take_while(|&&b| b == b'=').count();
+    (bytes[0] == b'[' && bytes.get(1 + level) == Some(&b'[')).then_some(level)
+}
+
+// How far into `bytes` the long bracket it opens is closed, if it is
+fn close_long(bytes: &[u8], level: usize) -> Option<usize> {
+    let close = format!("]{}]", "=".repeat(level));
+    let body = level + 2;
+    bytes[body..]
+        .windows(close.len())
+        .position(|w| w == close.as_bytes())
+        .map(|end| body + end + close.len())
+}
+
+// Keywords, strings, numbers and comments in their own colors, and `partner` picked out as the
+// bracket matching the one at the cursor
+fn highlight(line: &str, partner: Option<usize>) -> String {
+    let bytes = line.as_bytes();
+    let mut out = String::with_capacity(line.len() * 2);
+    let mut at = 0;
+
+    while at < bytes.len() {
+        let rest = &bytes[at..];
+        let start = at;
+        match rest[0] {
+            b'-' if rest.starts_with(b"--") => {
+                at = match long_bracket(&rest[2..]).and_then(|level| close_long(&rest[2..], level))
+                {
+                    Some(len) => at + 2 + len,
+                    None if long_bracket(&rest[2..]).is_some() => bytes.len(),
+                    None => rest
+                        .iter()
+                        .position(|&b| b == b'\n')
+                        .map_or(bytes.len(), |end| at + end),
+                };
+                paint(&mut out, COMMENT, &line[start..at]);
+            }
+            b'[' if let Some(level) = long_bracket(rest) => {
+                at = close_long(rest, level).map_or(bytes.len(), |len| at + len);
+                paint(&mut out, STRING, &line[start..at]);
+            }
+            quote @ (b'\'' | b'"') => {
+                let mut escaped = false;
+                at = line[at + 1..]
+                    .char_indices()
+                    .find_map(|(idx, c)| {
+                        let end = match c {
+                            _ if escaped => None,
+                            '\n' => Some(idx),
+                            _ if c == quote as char => Some(idx + 1),
+                            _ => None,
+                        };
+                        escaped = !escaped && c == '\\';
+                        end
+                    })
+                    .map_or(bytes.len(), |end| at + 1 + end);
+                paint(&mut out, STRING, &line[start..at]);
+            }
+            b if b.is_ascii_digit() => {
+                while bytes
+                    .get(at)
+                    .is_some_and(|&b| b.is_ascii_alphanumeric() || b == b'.')
+                {
+                    at += 1;
+                }
+                paint(&mut out, NUMBER, &line[start..at]);
+            }
+            b if b.is_ascii_alphabetic() || b == b'_' => {
+                while bytes
+                    .get(at)
+                    .is_some_and(|&b| b.is_ascii_alphanumeric() || b == b'_')
+                {
+                    at += 1;
+                }
+                let word = &line[start..at];
+                if KEYWORDS.contains(&word) {
+                    paint(&mut out, KEYWORD, word);
+                } else {
+                    out.push_str(word);
+                }
+            }
+            _ if partner == Some(at) => {
+                at += 1;
+                paint(&mut out, BRACKET, &line[start..at]);
+            }
+            _ => {
+                let len = line[at..].chars().next().map_or(1, char::len_utf8);
+                at += len;
+                out.push_str(&line[start..at]);
+            }
+        }
+    }
+
+    out
+}
+
+fn paint(out: &mut String, color: &str, text: &str) {
+    out.push_str(color);
+    out.push_str(text);
+    out.push_str(RESET);
+}
+
+// Where the bracket matching the one under or just before the cursor is
+fn matching_bracket(line: &str, pos: usize) -> Option<usize> {
+    let bytes = line.as_bytes();
+    let (at, bracket) = [pos, pos.checked_sub(1)?]
+        .into_iter()
+        .find_map(|at| Some((at, *bytes.get(at).filter(|b| b"()[]{}".contains(b))?)))?;
+
+    let (partner, forward) = match bracket {
+        b'(' => (b')', true),
+        b'[' => (b']', true),
+        b'{' => (b'}', true),
+        b')' => (b'(', false),
+        b']' => (b'[', false),
+        _ => (b'{', false),
+    };
 
-    loop {
-        let line = rl.readline(">> ")?;
-        info!("Got line: {line}");
+    let mut depth = 0;
+    let found = |(idx, &b): (usize, &u8)| {
+        if b == bracket {
+            depth += 1;
+        } else if b == partner {
+            if depth == 0 {
+                return Some(idx);
+            }
+            depth -= 1;
+        }
+        None
+    };
+
+    if forward {
+        bytes.iter().enumerate().skip(at + 1).find_map(found)
+    } else {
+        bytes[..at].iter().enumerate().rev().find_map(found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        if line.as_str() == "exit" {
-            break Ok(());
+    #[test]
+    fn complete_statements() {
+        for lua in [
+            "print(1)",
+            "output[1].volts = 3",
+            "function f() return 1 end",
+            "for i = 1, 4 do output[i].volts = 0 end",
+            "repeat x = x + 1 until x > 3",
+            "t = { a = { 1, 2 }, b = '}' }",
+            "",
+        ] {
+            assert!(!unfinished(lua), "{lua}");
         }
+    }
 
-        if line.len() > 64 {
-            writer.write_delimited(&line).await?;
-        } else {
-            writer.write_all(&line).await?;
+    #[test]
+    fn open_blocks() {
+        for lua in [
+            "function f()",
+            "if x then",
+            "for i = 1, 4 do",
+            "repeat",
+            "print(",
+            "t = {",
+            // Still one deep once the inner block closes
+            "function f()\n  if x then\n    y()\n  end",
+            "metro[1].event = function(c)\n  for i = 1, 4 do\n    output[i].volts = c % 8\n  end",
+        ] {
+            assert!(unfinished(lua), "{lua}");
         }
+
+        assert!(!unfinished(
+            "function f()\n  if x then\n    y()\n  end\nend"
+        ));
+    }
+
+    // Keywords and brackets in strings and comments don't count
+    #[test]
+    fn strings_and_comments() {
+        for lua in [
+            "print('function do (')",
+            "print(\"if \\\" then\")",
+            "x = 1 -- do something {",
+            "s = [[ function\n do ]]",
+            "s = [==[ ]] end ]==]",
+            "--[[ if x then\n end end ]] y = 1",
+        ] {
+            assert!(!unfinished(lua), "{lua}");
+        }
+
+        // Long strings and comments can span lines
+        assert!(unfinished("s = [[ the first line"));
+        assert!(unfinished("s = [=[ closed ]] early"));
+        assert!(unfinished("--[[ a comment that"));
+        // A short string can't, so that's crow's to report rather than more to wait for
+        assert!(!unfinished("print('oops"));
     }
 }
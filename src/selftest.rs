@@ -0,0 +1,373 @@
+use super::{
+    crow::{Crow, ReadTimeouts, ReconnectPolicy},
+    error::*,
+    protocol::{self, CheckStatus, Client, Message},
+    server,
+};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+// Short so the heartbeat step doesn't hold things up, long enough for the other steps to get
+// through without being pinged
+const HEARTBEAT: Duration = Duration::from_millis(200);
+const TOKEN: &str = "pidgeon-self-test";
+
+fn fail(step: &str, detail: impl std::fmt::Debug) -> Error {
+    Error::SelfTest(format!("{step}: {detail:?}"))
+}
+
+// The whole stack minus hardware: a simulated crow behind a server on an ephemeral port, driven by
+// the same client an editor would use
+pub async fn run(bind: IpAddr) -> Result<()> {
+    for exclusive in [false, true] {
+        println!("{} server", if exclusive { "exclusive" } else { "shared" });
+        let (crow, mock) = Crow::simulated()?;
+
+        let listener = server::make_conn(bind, 0)?;
+        let addr = listener.local_addr()?;
+        let config = server::Config {
+            bind,
+            port: addr.port(),
+            reconnect: ReconnectPolicy::default(),
+            log_requests: false,
+            max_message: protocol::BUFSIZE,
+            exclusive,
+            heartbeat: Some(HEARTBEAT),
+            heartbeat_timeout: HEARTBEAT,
+            response: ReadTimeouts {
+                idle: Duration::from_millis(50),
+                response: REPLY_TIMEOUT,
+            },
+            auth_token: Some(TOKEN.into()),
+        };
+        let server = tokio::spawn(server::serve(listener, crow, config));
+
+        let result = session(addr, exclusive).await;
+
+        server.abort();
+        mock.abort();
+        result?;
+    }
+
+    println!("self-test passed");
+    Ok(())
+}
+
+async fn request(
+    client: &mut Client,
+    request_id: u64,
+    lua: &str,
+    step: &str,
+) -> Result<Vec<Message>> {
+    client
+        .send(&Message::Success {
+            request_id,
+            contents: lua.into(),
+        })
+        .await?;
+
+    replies(client, request_id, step).await
+}
+
+// Everything the server sends back for one request, up to its Done
+async fn replies(client: &mut Client, request_id: u64, step: &str) -> Result<Vec<Message>> {
+    let mut replies = Vec::new();
+    loop {
+        match tokio::time::timeout(REPLY_TIMEOUT, client.recv()).await {
+            Ok(Ok(Message::Done { request_id: id })) if id == request_id => break Ok(replies),
+            Ok(Ok(reply)) => replies.push(reply),
+            Ok(Err(err)) => break Err(fail(step, err)),
+            Err(_) => break Err(fail(step, "no reply")),
+        }
+    }
+}
+
+// A request the crow answers with exactly these lines
+async fn expect(
+    client: &mut Client,
+    request_id: u64,
+    lua: &str,
+    want: &[&str],
+    step: &str,
+) -> Result<()> {
+    let replies = request(client, request_id, lua, step).await?;
+    let lines = replies
+        .iter()
+        .map(|reply| match reply {
+            Message::Success {
+                request_id: id,
+                contents,
+            } if *id == request_id => Some(contents.as_str()),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>();
+
+    if lines.as_deref() == Some(want) {
+        println!("{step}: ok");
+        Ok(())
+    } else {
+        Err(fail(step, replies))
+    }
+}
+
+async fn say(client: &mut Client, request_id: u64, step: &str) -> Result<()> {
+    let lua = "print('pidgeon self-test')";
+    expect(client, request_id, lua, &["pidgeon self-test"], step).await
+}
+
+async fn session(addr: SocketAddr, exclusive: bool) -> Result<()> {
+    // Nothing gets past a server with a token without it
+    let mut conn = TcpStream::connect(addr).await?;
+    let mut buf = Vec::new();
+    protocol::write_message(&mut conn, &Message::Check { version: None }).await?;
+    match tokio::time::timeout(REPLY_TIMEOUT, protocol::read_message(&mut conn, &mut buf)).await {
+        Ok(Ok(Message::Failure { contents, .. })) if contents == "unauthorized" => {
+            println!("no token: ok")
+        }
+        Ok(reply) => return Err(fail("no token", reply)),
+        Err(_) => return Err(fail("no token", "no reply")),
+    }
+    match Client::new(addr).with_token("wrong").check().await {
+        CheckStatus::Unauthorized => println!("wrong token: ok"),
+        status => return Err(fail("wrong token", status)),
+    }
+
+    // A client from a future protocol is told what this server speaks instead of misunderstood
+    let mut conn = TcpStream::connect(addr).await?;
+    let mut buf = Vec::new();
+    let token = TOKEN.into();
+    protocol::write_message(&mut conn, &Message::Auth { token }).await?;
+    let version = Some(protocol::PROTOCOL_VERSION + 1);
+    protocol::write_message(&mut conn, &Message::Check { version }).await?;
+    match tokio::time::timeout(REPLY_TIMEOUT, protocol::read_message(&mut conn, &mut buf)).await {
+        Ok(Ok(Message::Failure { contents, .. })) if contents.contains("speaks v1") => {
+            println!("protocol mismatch: ok")
+        }
+        Ok(reply) => return Err(fail("protocol mismatch", reply)),
+        Err(_) => return Err(fail("protocol mismatch", "no reply")),
+    }
+
+    let mut client = Client::new(addr).with_token(TOKEN);
+
+    match client.check().await {
+        CheckStatus::Available => println!("check: ok"),
+        status => return Err(fail("check", status)),
+    }
+
+    client.start().await?;
+    println!("start: ok");
+
+    say(&mut client, 1, "send").await?;
+
+    client.ping().await?;
+    println!("ping: ok");
+
+    // Every line of a multi-line reply comes back, not just the first
+    let lua = "print('one'); print('two'); print('three')";
+    expect(
+        &mut client,
+        5,
+        lua,
+        &["one", "two", "three"],
+        "multi-line reply",
+    )
+    .await?;
+
+    // A statement that prints nothing still gets an answer
+    match request(&mut client, 4, "x = 1", "silent reply")
+        .await?
+        .as_slice()
+    {
+        [
+            Message::Failure {
+                request_id: Some(4),
+                contents,
+            },
+        ] if contents == "timeout" => println!("silent reply: ok"),
+        replies => return Err(fail("silent reply", replies)),
+    }
+
+    // Cancelling stops the wait on the crow right away, instead of after the reply timeout
+    client
+        .send(&Message::Success {
+            request_id: 6,
+            contents: "x = 2".into(),
+        })
+        .await?;
+    client
+        .send(&Message::Cancel {
+            request_id: 6,
+            clear: false,
+        })
+        .await?;
+    match replies(&mut client, 6, "cancel").await?.as_slice() {
+        [
+            Message::Failure {
+                request_id: Some(6),
+                contents,
+            },
+        ] if contents == "cancelled" => println!("cancel: ok"),
+        replies => return Err(fail("cancel", replies)),
+    }
+
+    let mut other = Client::new(addr).with_token(TOKEN);
+    if !exclusive {
+        // A second client gets its own turn with the crow, and only its own replies
+        match other.check().await {
+            CheckStatus::Available => println!("check while shared: ok"),
+            status => return Err(fail("check while shared", status)),
+        }
+        other.start().await?;
+        say(&mut other, 2, "send while shared").await?;
+        say(&mut client, 3, "send alongside").await?;
+        other.stop().await?;
+
+        return stop(client, addr).await;
+    }
+
+    // A second client is turned away while the first holds the crow
+    match other.check().await {
+        CheckStatus::Busy => println!("check while busy: ok"),
+        status => return Err(fail("check while busy", status)),
+    }
+    match other.start().await {
+        Err(Error::SessionRefused(contents)) if contents == "BUSY" => {
+            println!("start while busy: ok")
+        }
+        res => return Err(fail("start while busy", res)),
+    }
+
+    stop(client, addr).await
+}
+
+// The server frees the crow once it notices the session is over, which can be just after we ask
+async fn wait_free(client: &Client, step: &str) -> Result<()> {
+    let deadline = tokio::time::Instant::now() + REPLY_TIMEOUT;
+    loop {
+        match client.check().await {
+            CheckStatus::Available => {
+                println!("{step}: ok");
+                break Ok(());
+            }
+            CheckStatus::Busy if tokio::time::Instant::now() < deadline => {
+                tokio::time::sleep(Duration::from_millis(10)).await
+            }
+            status => break Err(fail(step, status)),
+        }
+    }
+}
+
+async fn stop(mut client: Client, addr: SocketAddr) -> Result<()> {
+    client.stop().await?;
+    println!("stop: ok");
+
+    wait_free(&client, "check after stop").await?;
+
+    // The Start is acknowledged before anything is answered, even a request sent right behind it
+    let mut conn = TcpStream::connect(addr).await?;
+    let mut buf = Vec::new();
+    let token = TOKEN.into();
+    protocol::write_message(&mut conn, &Message::Auth { token }).await?;
+    protocol::write_message(&mut conn, &Message::Start { version: None }).await?;
+    let ack = Message::Success {
+        request_id: 7,
+        contents: "print('ack')".into(),
+    };
+    protocol::write_message(&mut conn, &ack).await?;
+    let mut got = Vec::new();
+    while !matches!(got.last(), Some(Message::Done { .. })) {
+        let reply = protocol::read_message(&mut conn, &mut buf);
+        match tokio::time::timeout(REPLY_TIMEOUT, reply).await {
+            Ok(Ok(reply)) => got.push(reply),
+            Ok(Err(err)) => return Err(fail("start ack", err)),
+            Err(_) => return Err(fail("start ack", got)),
+        }
+    }
+    match got.as_slice() {
+        [
+            Message::Affirm,
+            Message::Success { contents, .. },
+            Message::Done { .. },
+        ] if contents == "ack" => {
+            println!("start ack: ok")
+        }
+        replies => return Err(fail("start ack", replies)),
+    }
+
+    // Closing without a Stop ends the session just the same
+    drop(conn);
+    wait_free(&client, "check after hang up").await?;
+
+    // A client that stops answering is pinged, then dropped
+    let mut conn = TcpStream::connect(addr).await?;
+    let mut buf = Vec::new();
+    let token = TOKEN.into();
+    protocol::write_message(&mut conn, &Message::Auth { token }).await?;
+    protocol::write_message(&mut conn, &Message::Start { version: None }).await?;
+    match tokio::time::timeout(REPLY_TIMEOUT, protocol::read_message(&mut conn, &mut buf)).await {
+        Ok(Ok(Message::Affirm)) => {}
+        Ok(reply) => return Err(fail("heartbeat", reply)),
+        Err(_) => return Err(fail("heartbeat", "no affirm")),
+    }
+    match tokio::time::timeout(REPLY_TIMEOUT, protocol::read_message(&mut conn, &mut buf)).await {
+        Ok(Ok(Message::Ping)) => println!("heartbeat: ok"),
+        Ok(reply) => return Err(fail("heartbeat", reply)),
+        Err(_) => return Err(fail("heartbeat", "no ping")),
+    }
+    match tokio::time::timeout(REPLY_TIMEOUT, protocol::read_message(&mut conn, &mut buf)).await {
+        Ok(Err(_)) => println!("heartbeat timeout: ok"),
+        Ok(reply) => return Err(fail("heartbeat timeout", reply)),
+        Err(_) => return Err(fail("heartbeat timeout", "still connected")),
+    }
+
+    // Garbage gets a Failure and the same connection can still be used
+    let mut conn = TcpStream::connect(addr).await?;
+    let mut buf = Vec::new();
+    protocol::write_frame(&mut conn, b"{not json").await?;
+    let token = TOKEN.into();
+    protocol::write_message(&mut conn, &Message::Auth { token }).await?;
+    protocol::write_message(&mut conn, &Message::Check { version: None }).await?;
+    match tokio::time::timeout(REPLY_TIMEOUT, protocol::read_message(&mut conn, &mut buf)).await {
+        Ok(Ok(Message::Failure { .. })) => println!("malformed message: ok"),
+        Ok(reply) => return Err(fail("malformed message", reply)),
+        Err(_) => return Err(fail("malformed message", "no reply")),
+    }
+    match tokio::time::timeout(REPLY_TIMEOUT, protocol::read_message(&mut conn, &mut buf)).await {
+        Ok(Ok(Message::Affirm)) => println!("check after malformed message: ok"),
+        Ok(reply) => return Err(fail("check after malformed message", reply)),
+        Err(_) => return Err(fail("check after malformed message", "no reply")),
+    }
+
+    // A length prefix no sane client would send is refused with a Failure, not a panic or an
+    // attempt to allocate it
+    let mut conn = TcpStream::connect(addr).await?;
+    let mut buf = Vec::new();
+    conn.write_u32(u32::MAX).await?;
+    let reply = protocol::read_message(&mut conn, &mut buf);
+    match tokio::time::timeout(REPLY_TIMEOUT, reply).await {
+        Ok(Ok(Message::Failure { .. })) => println!("oversized message: ok"),
+        Ok(reply) => return Err(fail("oversized message", reply)),
+        Err(_) => return Err(fail("oversized message", "no reply")),
+    }
+
+    match client.check().await {
+        CheckStatus::Available => println!("check after oversized message: ok"),
+        status => return Err(fail("check after oversized message", status)),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The same run as `pidgeon self-test`, so CI goes through it without any hardware
+    #[tokio::test]
+    async fn session_against_simulated_crow() {
+        run(server::DEFAULT_BIND).await.unwrap();
+    }
+}
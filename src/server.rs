@@ -1,137 +1,518 @@
-use super::{crow::Crow, error::Result};
-use futures::lock::Mutex;
-use serde::{Deserialize, Serialize};
+use super::protocol::{self, Message, Message::*};
+use super::{
+    crow::{Crow, ReadTimeouts, ReconnectPolicy},
+    error::{Error, Result},
+    event::CrowEvent,
+};
+use std::collections::VecDeque;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpSocket};
+use tokio::sync::{Mutex, broadcast, mpsc, watch};
+use tokio::time::Instant;
 use tracing::{error, info};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "status")]
-enum Message {
-    Success {
-        request_id: u64,
-        contents: String,
-    },
-    Check,
-    Start,
-    Affirm,
-    Failure {
-        request_id: Option<u64>,
-        contents: String,
-    },
-}
-use Message::*;
-
-const BUFSIZE: usize = 512 * 512;
+// Anything the length-prefixed protocol can run over
+trait Conn: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Conn for T {}
+
+// What came off the wire, parsed or not
+enum Frame {
+    Message(Message),
+    Malformed(Error),
+}
 
 struct Server {
-    backing_buf: [u8; BUFSIZE],
-    conn: TcpStream,
+    backing_buf: Vec<u8>,
+    conn: Box<dyn Conn>,
+    log_requests: bool,
+    max_message: usize,
 }
 
-impl Server {
-    async fn read_bytes(&mut self) -> Result<&[u8]> {
-        let len = self.conn.read_u32().await?;
-        info!("got len: {len}");
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+    #[cfg(feature = "tls")]
+    Tls(TcpListener, tokio_rustls::TlsAcceptor),
+}
 
-        self.conn
-            .read_exact(&mut self.backing_buf[0..len as usize])
-            .await?;
-        info!("read {len} bytes successfully");
+// A connection as it comes off the listener. TLS ones still have their handshake to do, which is
+// left to the connection's own task so a client that never finishes it can't hold up the rest
+enum Incoming {
+    Ready(Box<dyn Conn>),
+    #[cfg(feature = "tls")]
+    Tls(tokio::net::TcpStream, tokio_rustls::TlsAcceptor),
+}
 
-        Ok(&self.backing_buf[0..len as usize])
+impl Incoming {
+    async fn open(self) -> Result<Box<dyn Conn>> {
+        match self {
+            Incoming::Ready(conn) => Ok(conn),
+            // A client that can't get through the TLS handshake never makes it to the protocol
+            #[cfg(feature = "tls")]
+            Incoming::Tls(conn, acceptor) => {
+                match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(conn)).await {
+                    Ok(Ok(conn)) => Ok(Box::new(conn)),
+                    Ok(Err(err)) => Err(Error::Tls(format!("handshake failed: {err}"))),
+                    Err(_) => Err(Error::Tls(format!(
+                        "handshake not done within {TLS_HANDSHAKE_TIMEOUT:?}"
+                    ))),
+                }
+            }
+        }
     }
+}
 
-    async fn write_bytes(&mut self, chunk: &[u8]) -> Result<()> {
-        let len = chunk.len();
-        debug_assert!(len < u32::MAX as usize);
+impl Listener {
+    async fn accept(&self) -> std::io::Result<(Incoming, String)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (conn, addr) = listener.accept().await?;
+                Ok((Incoming::Ready(Box::new(conn)), addr.to_string()))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (conn, _) = listener.accept().await?;
+                Ok((Incoming::Ready(Box::new(conn)), "unix socket".into()))
+            }
+            #[cfg(feature = "tls")]
+            Listener::Tls(listener, acceptor) => {
+                let (conn, addr) = listener.accept().await?;
+                Ok((Incoming::Tls(conn, acceptor.clone()), addr.to_string()))
+            }
+        }
+    }
+}
 
-        self.conn.write_u32(len as u32).await?;
-        info!("wrote prefix {len}");
-        self.conn.write_all(chunk).await?;
-        info!("wrote {len} byte successfully");
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::Tcp(listener)
+    }
+}
 
-        Ok(())
+#[cfg(unix)]
+impl From<tokio::net::UnixListener> for Listener {
+    fn from(listener: tokio::net::UnixListener) -> Self {
+        Listener::Unix(listener)
     }
+}
+
+// Unsolicited crow output is checked for this often, and held for clients this deep
+const EVENT_POLL_INTERVAL: Duration = Duration::from_millis(20);
+const EVENT_BACKLOG: usize = 256;
+// Before looking again after the crow couldn't be read
+const EVENT_RETRY: Duration = Duration::from_secs(1);
+
+// How long a client gets to finish the TLS handshake before it's dropped
+#[cfg(feature = "tls")]
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long sessions get to finish what they're doing once the server is told to stop
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+const LOG_CONTENTS_LEN: usize = 120;
+
+fn truncated(contents: &str) -> String {
+    match contents.char_indices().nth(LOG_CONTENTS_LEN) {
+        Some((idx, _)) => format!("{}... ({} bytes)", &contents[..idx], contents.len()),
+        None => contents.to_string(),
+    }
+}
+
+// A message as it should appear in the request log, big script uploads cut down to size
+fn summary(msg: &Message) -> String {
+    match msg {
+        Success {
+            request_id,
+            contents,
+        } => format!("Success #{request_id}: {:?}", truncated(contents)),
+        Failure {
+            request_id,
+            contents,
+        } => format!("Failure #{request_id:?}: {:?}", truncated(contents)),
+        // Kept out of the logs
+        Auth { .. } => "Auth".into(),
+        other => format!("{other:?}"),
+    }
+}
+
+impl Server {
+    // Writes nothing, so it can lose a select without leaving half a Failure on the wire. What it
+    // couldn't make sense of is handed to `checked` to answer once the select's done
+    async fn receive(&mut self) -> Result<Frame> {
+        let frame =
+            protocol::read_frame(&mut self.conn, &mut self.backing_buf, self.max_message).await?;
 
+        Ok(match self.parse(&frame) {
+            Ok(msg) => Frame::Message(msg),
+            Err(err) => Frame::Malformed(err),
+        })
+    }
+
+    // A frame that isn't a message is answered with a Failure and skipped, the framing around it
+    // is still sound so the connection carries on. An oversized one is answered before the
+    // connection is dropped, the rest of it is still sitting in the socket
+    async fn checked(&mut self, received: Result<Frame>) -> Result<Option<Message>> {
+        match received {
+            Ok(Frame::Message(msg)) => Ok(Some(msg)),
+            Ok(Frame::Malformed(err)) => {
+                self.write_message(Failure {
+                    request_id: None,
+                    contents: format!("malformed message: {err}"),
+                })
+                .await?;
+                Ok(None)
+            }
+            Err(err @ Error::MessageTooLarge { .. }) => {
+                self.write_message(Failure {
+                    request_id: None,
+                    contents: err.to_string(),
+                })
+                .await?;
+                Err(err)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    // The next message, for when there's nothing to race it against
     async fn read_message(&mut self) -> Result<Message> {
-        let bytes = self.read_bytes().await?;
-        Ok(serde_json::from_slice(bytes)?)
+        loop {
+            let received = self.receive().await;
+            if let Some(msg) = self.checked(received).await? {
+                return Ok(msg);
+            }
+        }
+    }
+
+    fn parse(&self, bytes: &[u8]) -> Result<Message> {
+        match serde_json::from_slice(bytes) {
+            Ok(msg) => {
+                if self.log_requests {
+                    info!(target: "pidgeon::server", "Request: {}", summary(&msg));
+                }
+                Ok(msg)
+            }
+            Err(err) => {
+                if self.log_requests {
+                    info!(target: "pidgeon::server",
+                        "Unparseable request ({err}): {:?}",
+                        truncated(&String::from_utf8_lossy(bytes))
+                    );
+                }
+                Err(err.into())
+            }
+        }
     }
 
     async fn write_message(&mut self, msg: Message) -> Result<()> {
-        let bytes = serde_json::to_vec(&msg)?;
-        self.write_bytes(&bytes).await
+        protocol::write_message(&mut self.conn, &msg).await
     }
 }
 
 const BACKLOG: u32 = 10;
 
-fn make_conn(port: u16) -> Result<TcpListener> {
-    let conn = TcpSocket::new_v4()?;
-    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
+pub const DEFAULT_BIND: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+pub fn make_conn(bind: IpAddr, port: u16) -> Result<TcpListener> {
+    let conn = match bind {
+        IpAddr::V4(_) => TcpSocket::new_v4()?,
+        IpAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    let addr = SocketAddr::new(bind, port);
     conn.set_reuseaddr(true)?;
     conn.bind(addr)?;
 
     Ok(conn.listen(BACKLOG)?)
 }
 
-async fn read_crow_response(crow: &mut Crow) -> Option<Result<String>> {
-    tokio::time::timeout(Duration::from_millis(200), crow.read_line())
-        .await
-        .ok()
+// What the client sent while a request was being answered
+enum Interrupt {
+    Cancelled { clear: bool },
 }
 
-async fn handle_conn(server: &mut Server, crow: &mut Crow) -> Result<()> {
+// Wait on the crow for `request_id`, meanwhile taking in whatever the client sends: Pings are
+// answered, a Cancel for this request stops the wait and anything else is queued for later.
+// Receiving is cancel-safe and replies are only written once the select's over, so nothing is
+// lost or cut short when the crow wins
+async fn interruptible<T>(
+    server: &mut Server,
+    pending: &mut VecDeque<Message>,
+    request_id: u64,
+    wait: impl Future<Output = T>,
+) -> Result<std::result::Result<T, Interrupt>> {
+    let mut wait = std::pin::pin!(wait);
+
     loop {
-        // Contents should be a Lua string
-        match server.read_message().await? {
-            // Content should be a valid lua string
-            Success {
-                request_id,
-                contents,
-            } => {
-                info!("Got message from LUA client");
+        let received = tokio::select! {
+            done = &mut wait => return Ok(Ok(done)),
+            received = server.receive() => received,
+        };
+        let Some(msg) = server.checked(received).await? else {
+            continue;
+        };
 
-                async fn write_chunk(crow: &mut Crow, chunk: &str) -> Result<()> {
-                    if chunk.len() >= 64 {
-                        crow.write_delimited(chunk).await
-                    } else {
-                        crow.write_all(chunk).await
-                    }
-                }
+        match msg {
+            Ping => server.write_message(Pong).await?,
+            Pong => {}
+            Cancel {
+                request_id: id,
+                clear,
+            } if id == request_id => {
+                return Ok(Err(Interrupt::Cancelled { clear }));
+            }
+            Cancel { request_id: id, .. } => cancel_queued(server, pending, id).await?,
+            other => pending.push_back(other),
+        }
+    }
+}
+
+async fn cancelled(server: &mut Server, request_id: u64) -> Result<()> {
+    server
+        .write_message(Failure {
+            request_id: Some(request_id),
+            contents: "cancelled".into(),
+        })
+        .await?;
+    server.write_message(Done { request_id }).await
+}
+
+// A request still waiting its turn is dropped before it reaches the crow. One that's already
+// been answered has nothing left to cancel
+async fn cancel_queued(
+    server: &mut Server,
+    pending: &mut VecDeque<Message>,
+    request_id: u64,
+) -> Result<()> {
+    let queued = pending
+        .iter()
+        .position(|msg| matches!(msg, Success { request_id: id, .. } if *id == request_id));
+
+    match queued {
+        Some(at) => {
+            pending.remove(at);
+            cancelled(server, request_id).await
+        }
+        None => {
+            info!(target: "pidgeon::server", "Nothing to cancel for #{request_id}");
+            Ok(())
+        }
+    }
+}
+
+async fn answer(
+    server: &mut Server,
+    crow: &mut Crow,
+    config: &Config,
+    pending: &mut VecDeque<Message>,
+    events: &broadcast::Sender<String>,
+    request_id: u64,
+    contents: &str,
+) -> Result<()> {
+    let mut written = crow.write_line(contents).await;
+    if let Err(err) = &written
+        && err.is_disconnect()
+    {
+        info!(target: "pidgeon::server", "Write to crow failed ({err}), reconnecting");
+        // Reopened in place behind the lock, so every session gets the crow back
+        written = match crow.reconnect(config.reconnect).await {
+            Ok(()) => crow.write_line(contents).await,
+            Err(err) => Err(err),
+        };
+    }
+
+    if let Err(err) = written {
+        let contents = match err {
+            Error::ReconnectFailed { .. } => {
+                format!("Crow disconnected and couldn't be reopened: {err}")
+            }
+            err => err.to_string(),
+        };
+        server
+            .write_message(Failure {
+                request_id: Some(request_id),
+                contents,
+            })
+            .await?;
+        return server.write_message(Done { request_id }).await;
+    }
 
-                if let Err(err) = write_chunk(crow, &contents).await {
+    // The first line gets the reply timeout, anything after it keeps coming until the crow goes
+    // quiet. Each line goes out as soon as it's read
+    let mut reply = interruptible(server, pending, request_id, crow.read_reply()).await?;
+    let deadline = Instant::now() + config.response.response;
+    'reply: loop {
+        let response = match reply {
+            Err(Interrupt::Cancelled { clear }) => {
+                info!(target: "pidgeon::server", "Request #{request_id} cancelled");
+                // The request's over either way, only the session is kept going
+                if clear && let Err(err) = crow.clear_script().await {
+                    error!(target: "pidgeon::server", "Couldn't clear the script for #{request_id}: {err}");
                     server
                         .write_message(Failure {
                             request_id: Some(request_id),
-                            contents: format!("{err}"),
+                            contents: format!(
+                                "cancelled, but the script couldn't be cleared: {err}"
+                            ),
                         })
                         .await?;
+                    return server.write_message(Done { request_id }).await;
                 }
+                return cancelled(server, request_id).await;
+            }
+            // End of stream
+            Ok(Ok(Some(line))) if line.is_empty() => break,
+            // One line per message, so the newline has done its job
+            Ok(Ok(Some(crow_response))) => Success {
+                request_id,
+                contents: crow_response.trim_end_matches(['\r', '\n']).to_string(),
+            },
+            // Said out loud, a client waiting on silence would wait forever
+            Ok(Ok(None)) => Failure {
+                request_id: Some(request_id),
+                contents: "timeout".into(),
+            },
+            // Whatever the crow was saying is gone, but the next request needn't be
+            Ok(Err(err)) if err.is_disconnect() => {
+                info!(target: "pidgeon::server", "Crow dropped mid-reply ({err}), reconnecting");
+                let contents = match crow.reconnect(config.reconnect).await {
+                    Ok(()) => format!("{err}, reconnected but the rest of the reply was lost"),
+                    Err(err) => format!("Crow disconnected and couldn't be reopened: {err}"),
+                };
+                Failure {
+                    request_id: Some(request_id),
+                    contents,
+                }
+            }
+            Ok(Err(err)) => Failure {
+                request_id: Some(request_id),
+                contents: err.to_string(),
+            },
+        };
+        let more = matches!(response, Success { .. });
+        server.write_message(response).await?;
+        if !more {
+            break;
+        }
 
-                if let Some(response) = read_crow_response(crow).await {
-                    let response = match response {
-                        Ok(crow_response) => Success {
-                            request_id,
-                            contents: crow_response,
-                        },
-                        Err(err) => Failure {
-                            request_id: Some(request_id),
-                            contents: err.to_string(),
-                        },
-                    };
+        let gap = (Instant::now() + config.response.idle).min(deadline);
+        reply = loop {
+            let next = tokio::time::timeout_at(gap, crow.read_line());
+            match interruptible(server, pending, request_id, next).await? {
+                // Something the crow printed by itself mid-reply, it isn't part of the answer
+                Ok(Ok(Ok(line))) if !line.is_empty() && !CrowEvent::parse(&line).is_response() => {
+                    let _ = events.send(line.trim_end_matches(['\r', '\n']).to_string());
+                }
+                Ok(Ok(line)) => break Ok(line.map(Some)),
+                Ok(Err(_)) => break 'reply,
+                Err(interrupt) => break Err(interrupt),
+            }
+        };
+    }
 
-                    server.write_message(response).await?;
+    server.write_message(Done { request_id }).await
+}
+
+// Each request takes the crow only for as long as it takes to answer, so sessions can interleave.
+// The lock hands the crow out in the order requests arrived, no client can starve another
+async fn handle_conn(server: &mut Server, shared: &Shared) -> Result<()> {
+    let Shared { crow, config, .. } = shared;
+    let mut events = shared.events.subscribe();
+    let mut shutdown = shared.shutdown.clone();
+
+    // When the client last said anything, and once a Ping has gone out, when it has to answer by
+    let mut heard = Instant::now();
+    let mut pong_due = None;
+    // Sent while an earlier request was being answered
+    let mut pending = VecDeque::new();
+
+    loop {
+        let msg = match pending.pop_front() {
+            Some(msg) => msg,
+            None => {
+                let heartbeat = async {
+                    match (config.heartbeat, pong_due) {
+                        (None, _) => std::future::pending().await,
+                        (Some(_), Some(due)) => tokio::time::sleep_until(due).await,
+                        (Some(interval), None) => tokio::time::sleep_until(heard + interval).await,
+                    }
+                };
+
+                tokio::select! {
+                    received = server.receive() => match server.checked(received).await? {
+                        Some(msg) => msg,
+                        None => continue,
+                    },
+                    event = events.recv() => {
+                        match event {
+                            Ok(contents) => server.write_message(Event { contents }).await?,
+                            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                                info!(target: "pidgeon::server", "Client fell behind, {missed} event(s) skipped")
+                            }
+                            // The server's gone, there's no crow to talk to any more
+                            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                        }
+                        continue;
+                    }
+                    // Anything already asked has been answered by now
+                    () = stopping(&mut shutdown) => {
+                        server
+                            .write_message(Failure {
+                                request_id: None,
+                                contents: "server shutting down".into(),
+                            })
+                            .await?;
+                        return Ok(());
+                    }
+                    () = heartbeat => {
+                        if pong_due.is_some() {
+                            return Err(Error::HeartbeatTimeout(config.heartbeat_timeout));
+                        }
+                        server.write_message(Ping).await?;
+                        pong_due = Some(Instant::now() + config.heartbeat_timeout);
+                        continue;
+                    }
                 }
             }
-            Failure { contents, .. } => error!("{contents}"),
+        };
+        // Anything from the client shows the link is still up, not just a Pong
+        heard = Instant::now();
+        pong_due = None;
+
+        match msg {
+            // Content should be a valid lua string
+            Success {
+                request_id,
+                contents,
+            } => {
+                info!(target: "pidgeon::server", "Got message from LUA client");
+                let mut crow = crow.lock().await;
+
+                answer(
+                    server,
+                    &mut crow,
+                    config,
+                    &mut pending,
+                    &shared.events,
+                    request_id,
+                    &contents,
+                )
+                .await?;
+            }
+            Cancel { request_id, .. } => cancel_queued(server, &mut pending, request_id).await?,
+            Failure { contents, .. } => error!(target: "pidgeon::server", "{contents}"),
+            Ping => server.write_message(Pong).await?,
+            Pong => {}
+            Stop => {
+                info!(target: "pidgeon::server", "Got STOP");
+                break Ok(());
+            }
             _ => {
-                info!("Got nonsense from LUA client");
+                info!(target: "pidgeon::server", "Got nonsense from LUA client");
                 server
                     .write_message(Failure {
                         request_id: None,
@@ -143,75 +524,520 @@ async fn handle_conn(server: &mut Server, crow: &mut Crow) -> Result<()> {
     }
 }
 
-pub async fn run(crow: Crow, port: u16) -> Result<()> {
-    info!("start server");
-    let listener = make_conn(port).expect("Failed to create socket");
-    info!("open socket");
+pub const DEFAULT_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub bind: IpAddr,
+    pub port: u16,
+    pub reconnect: ReconnectPolicy,
+    pub log_requests: bool,
+    pub max_message: usize,
+    // One session at a time, anyone else is turned away BUSY until it ends
+    pub exclusive: bool,
+    // Ping a session that's gone quiet this long, and drop it if there's no answer in time
+    pub heartbeat: Option<Duration>,
+    pub heartbeat_timeout: Duration,
+    // How long a multi-line reply can go quiet before it's over, and its cap
+    pub response: ReadTimeouts,
+    // When set, a client's first message has to be an Auth carrying this
+    pub auth_token: Option<String>,
+}
+
+pub async fn run(crow: Crow, config: Config) -> Result<()> {
+    info!(target: "pidgeon::server", "start server");
+    let listener = make_conn(config.bind, config.port)?;
+    info!(target: "pidgeon::server", "open socket");
+
+    // Port 0 binds whatever's free, so say where we ended up
+    println!("pidgeon listening on {}", listener.local_addr()?);
+
+    serve(listener, crow, config).await
+}
+
+// Same protocol with TLS over each TCP connection, for serving beyond localhost
+#[cfg(feature = "tls")]
+pub async fn run_tls(
+    crow: Crow,
+    config: Config,
+    acceptor: tokio_rustls::TlsAcceptor,
+) -> Result<()> {
+    let listener = make_conn(config.bind, config.port)?;
+    println!("pidgeon listening with TLS on {}", listener.local_addr()?);
+
+    serve(Listener::Tls(listener, acceptor), crow, config).await
+}
+
+// Same protocol over a unix socket, for clients on the same machine. The socket file is removed
+// again on shutdown
+#[cfg(unix)]
+pub async fn run_unix(crow: Crow, path: &std::path::Path, config: Config) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
 
+    // Left behind by a server that didn't get to clean up
+    if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = tokio::net::UnixListener::bind(path)?;
+    println!("pidgeon listening on {}", path.display());
+
+    let res = serve(listener, crow, config).await;
+
+    std::fs::remove_file(path)?;
+    res
+}
+
+// A client closing its end, whether or not it said Stop first, is how sessions normally end
+fn hung_up(err: &Error) -> bool {
+    use std::io::ErrorKind::*;
+
+    match err {
+        Error::ConnectionClosed => true,
+        Error::IO(err) => matches!(err.kind(), ConnectionReset | ConnectionAborted | BrokenPipe),
+        _ => false,
+    }
+}
+
+// Takes as long whichever byte differs, so a wrong token doesn't give away how much of it was right
+fn tokens_match(given: &[u8], expected: &[u8]) -> bool {
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+// Held for the length of an exclusive session, the crow is freed however the session ends, panics included
+struct Session(Arc<AtomicBool>);
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+// Lines crow prints by itself between requests, from metros, streams and the like. The crow is
+// only looked at while someone's listening, and only for a moment each time so requests get it
+async fn poll_events(
+    crow: Arc<Mutex<Crow>>,
+    events: broadcast::Sender<String>,
+    reconnect: ReconnectPolicy,
+) {
+    loop {
+        tokio::time::sleep(EVENT_POLL_INTERVAL).await;
+        if events.receiver_count() == 0 {
+            continue;
+        }
+
+        let line = crow.lock().await.try_read_line().await;
+        match line {
+            Ok(Some(line)) => {
+                let _ = events.send(line.trim_end_matches(['\r', '\n']).to_string());
+            }
+            Ok(None) => {}
+            // Reopened here too, so an unplug while nobody's sending doesn't wait on a request
+            Err(err) if err.is_disconnect() => {
+                info!(target: "pidgeon::server", "Crow dropped ({err}), reconnecting");
+                if let Err(err) = crow.lock().await.reconnect(reconnect).await {
+                    error!(target: "pidgeon::server", "Couldn't reopen the crow: {err}");
+                    tokio::time::sleep(EVENT_RETRY).await;
+                }
+            }
+            Err(err) => {
+                info!(target: "pidgeon::server", "Couldn't poll the crow for events: {err}");
+                tokio::time::sleep(EVENT_RETRY).await;
+            }
+        }
+    }
+}
+
+// What every connection's task needs from the server
+#[derive(Clone)]
+struct Shared {
+    crow: Arc<Mutex<Crow>>,
+    busy: Arc<AtomicBool>,
+    events: broadcast::Sender<String>,
+    // Set once the server is going down
+    shutdown: watch::Receiver<bool>,
+    // Held by every connection, the server knows they're all done once the last one's dropped
+    _alive: mpsc::Sender<()>,
+    config: Config,
+}
+
+// Runs until Ctrl-C, then stops taking connections, lets sessions finish the request they're on
+// and returns Ok so a service manager sees a clean exit
+pub async fn serve(listener: impl Into<Listener>, crow: Crow, config: Config) -> Result<()> {
+    let listener = listener.into();
     let crow = Arc::new(Mutex::new(crow));
-    let busy = AtomicBool::new(false);
+    let (events, _) = broadcast::channel(EVENT_BACKLOG);
+    let (stop, shutdown) = watch::channel(false);
+    let (alive, mut drained) = mpsc::channel(1);
+
+    let shared = Shared {
+        crow: crow.clone(),
+        busy: Arc::new(AtomicBool::new(false)),
+        events: events.clone(),
+        shutdown,
+        _alive: alive,
+        config: config.clone(),
+    };
 
+    let res = tokio::select! {
+        res = accept(&listener, &shared) => res,
+        () = poll_events(crow.clone(), events, config.reconnect) => Ok(()),
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    };
+
+    println!("pidgeon shutting down");
+    let _ = stop.send(true);
+    drop(shared);
+    // Every sender gone closes the channel
+    if tokio::time::timeout(SHUTDOWN_GRACE, drained.recv())
+        .await
+        .is_err()
+    {
+        error!(target: "pidgeon::server", "Sessions still busy after {SHUTDOWN_GRACE:?}, leaving them");
+    }
+
+    res
+}
+
+// The guard wait_for hands back isn't Send, so it's dropped here rather than held in a select
+async fn stopping(shutdown: &mut watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|&down| down).await;
+}
+
+async fn accept(listener: &Listener, shared: &Shared) -> Result<()> {
     loop {
-        let (conn, addr) = listener.accept().await?;
-        info!("Got connection on {addr:?}");
+        let (incoming, addr) = listener.accept().await?;
+        info!(target: "pidgeon::server", "Got connection from {addr}");
 
-        let mut server = Server {
-            backing_buf: [0; BUFSIZE],
-            conn,
-        };
+        // Each connection gets its own task from the start, so a client that stalls or misbehaves
+        // before its session begins only holds itself up
+        let shared = shared.clone();
+        tokio::spawn(async move {
+            let open = async {
+                let server = Server {
+                    backing_buf: Vec::new(),
+                    conn: incoming.open().await?,
+                    log_requests: shared.config.log_requests,
+                    max_message: shared.config.max_message,
+                };
+                handshake(server, &shared).await
+            };
 
-        let read = server.conn.read_u32().await?;
-        let _ = server
-            .conn
-            .read_exact(&mut server.backing_buf[0..read as usize])
-            .await?;
-        let message = serde_json::from_slice(&server.backing_buf[0..read as usize])?;
+            let mut shutdown = shared.shutdown.clone();
+            let res = tokio::select! {
+                res = open => res,
+                () = stopping(&mut shutdown) => Ok(()),
+            };
 
-        match message {
-            Start => {
-                info!("Got START");
-                if busy.load(Ordering::SeqCst) {
-                    info!("BUSY - client rejected");
-                    server
-                        .write_message(Failure {
-                            request_id: None,
-                            contents: "BUSY".into(),
-                        })
-                        .await?;
-                } else {
-                    info!("crow FREE - connection opened");
-                    let crow = crow.clone();
-
-                    let _ = tokio::spawn(async move {
-                        let mut crow = crow.lock().await;
-                        handle_conn(&mut server, &mut crow)
-                            .await
-                            .expect("FAILED HANDLE CONN")
+            match res {
+                Ok(()) => {}
+                Err(err) if hung_up(&err) => {
+                    info!(target: "pidgeon::server", "Client {addr} left during handshake")
+                }
+                Err(err) => {
+                    error!(target: "pidgeon::server", "Client {addr} dropped during handshake: {err}")
+                }
+            }
+        });
+    }
+}
+
+async fn handshake(mut server: Server, shared: &Shared) -> Result<()> {
+    let Shared { busy, config, .. } = shared;
+
+    // The handshake goes through the same framing as the rest of the session. Anything a client
+    // pipelines right behind its Start stays in the server's buffer for handle_conn to pick up
+    let mut msg = server.read_message().await?;
+
+    // Without a token configured an Auth is let through, so a client can always send one
+    match (&config.auth_token, msg) {
+        (Some(expected), Auth { token }) if tokens_match(token.as_bytes(), expected.as_bytes()) => {
+            msg = server.read_message().await?
+        }
+        (Some(_), _) => {
+            info!(target: "pidgeon::server", "Unauthorized client rejected");
+            return server
+                .write_message(Failure {
+                    request_id: None,
+                    contents: "unauthorized".into(),
+                })
+                .await;
+        }
+        (None, Auth { .. }) => msg = server.read_message().await?,
+        (None, other) => msg = other,
+    }
+
+    if let Start {
+        version: Some(version),
+    }
+    | Check {
+        version: Some(version),
+    } = msg
+        && version != protocol::PROTOCOL_VERSION
+    {
+        info!(target: "pidgeon::server", "Client speaks protocol v{version}, rejected");
+        return server
+            .write_message(Failure {
+                request_id: None,
+                contents: format!(
+                    "protocol v{version} isn't supported, this server speaks v{}",
+                    protocol::PROTOCOL_VERSION
+                ),
+            })
+            .await;
+    }
+
+    match msg {
+        Start { .. } => {
+            info!(target: "pidgeon::server", "Got START");
+            // Marked before the session starts so a client arriving right behind this one is
+            // turned away rather than left waiting on the lock
+            if config.exclusive && busy.swap(true, Ordering::SeqCst) {
+                info!(target: "pidgeon::server", "BUSY - client rejected");
+                return server
+                    .write_message(Failure {
+                        request_id: None,
+                        contents: "BUSY".into(),
                     })
                     .await;
-                }
             }
 
-            Check => {
-                if busy.load(Ordering::SeqCst) {
-                    server
-                        .write_message(Failure {
-                            request_id: None,
-                            contents: "BUSY".into(),
-                        })
-                        .await?
-                } else {
-                    server.write_message(Affirm).await?
+            info!(target: "pidgeon::server", "Session opened");
+            let session = config.exclusive.then(|| Session(busy.clone()));
+            // Before anything else goes out, so the client knows it's in rather than left guessing
+            server.write_message(Affirm).await?;
+            let shared = shared.clone();
+
+            tokio::spawn(async move {
+                let _session = session;
+
+                match handle_conn(&mut server, &shared).await {
+                    Ok(()) => info!(target: "pidgeon::server", "Session ended"),
+                    Err(err) if hung_up(&err) => {
+                        info!(target: "pidgeon::server", "Client disconnected, session ended")
+                    }
+                    Err(err) => error!(target: "pidgeon::server", "Session ended early: {err}"),
                 }
-            }
-            _ => {
+            });
+
+            Ok(())
+        }
+        Check { .. } => {
+            if busy.load(Ordering::SeqCst) {
                 server
                     .write_message(Failure {
                         request_id: None,
-                        contents: "don't understand".into(),
+                        contents: "BUSY".into(),
                     })
-                    .await?
+                    .await
+            } else {
+                server.write_message(Affirm).await
+            }
+        }
+        Ping => server.write_message(Pong).await,
+        _ => {
+            server
+                .write_message(Failure {
+                    request_id: None,
+                    contents: "don't understand".into(),
+                })
+                .await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpStream;
+
+    const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+    // A server on an ephemeral port in front of a simulated crow, stopped when the test's runtime is
+    async fn spawn(bind: IpAddr, exclusive: bool) -> SocketAddr {
+        let (crow, _mock) = Crow::simulated().unwrap();
+        let listener = make_conn(bind, 0).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Config {
+            bind,
+            port: addr.port(),
+            reconnect: ReconnectPolicy::default(),
+            log_requests: false,
+            max_message: protocol::BUFSIZE,
+            exclusive,
+            heartbeat: None,
+            heartbeat_timeout: DEFAULT_HEARTBEAT_TIMEOUT,
+            response: ReadTimeouts {
+                idle: Duration::from_millis(50),
+                response: REPLY_TIMEOUT,
+            },
+            auth_token: None,
+        };
+        tokio::spawn(serve(listener, crow, config));
+
+        addr
+    }
+
+    async fn reply(conn: &mut TcpStream, buf: &mut Vec<u8>) -> Message {
+        tokio::time::timeout(REPLY_TIMEOUT, protocol::read_message(conn, buf))
+            .await
+            .expect("no reply")
+            .unwrap()
+    }
+
+    fn framed(msgs: &[Message]) -> Vec<u8> {
+        msgs.iter()
+            .flat_map(|msg| {
+                let json = serde_json::to_vec(msg).unwrap();
+                let mut frame = (json.len() as u32).to_be_bytes().to_vec();
+                frame.extend(json);
+                frame
+            })
+            .collect()
+    }
+
+    async fn send(conn: &mut TcpStream, msg: Message) {
+        protocol::write_message(conn, &msg).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn check_over_ipv6() {
+        let addr = spawn(IpAddr::V6(std::net::Ipv6Addr::LOCALHOST), true).await;
+        assert!(addr.is_ipv6());
+
+        let status = protocol::Client::new(addr).check().await;
+        assert!(
+            matches!(status, protocol::CheckStatus::Available),
+            "{status:?}"
+        );
+    }
+
+    // A length prefix past the limit is answered with a Failure, not a panic or an attempt to
+    // allocate it, and the server carries on
+    #[tokio::test]
+    async fn oversized_prefix_is_refused() {
+        let addr = spawn(DEFAULT_BIND, true).await;
+
+        for len in [protocol::BUFSIZE as u32 + 1, u32::MAX] {
+            let mut conn = TcpStream::connect(addr).await.unwrap();
+            let mut buf = Vec::new();
+            conn.write_u32(len).await.unwrap();
+            match reply(&mut conn, &mut buf).await {
+                Failure { contents, .. } => assert!(contents.contains("over the"), "{contents}"),
+                reply => panic!("expected a Failure, got {reply:?}"),
+            }
+        }
+
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        let mut buf = Vec::new();
+        send(&mut conn, Check { version: None }).await;
+        assert!(matches!(reply(&mut conn, &mut buf).await, Affirm));
+    }
+
+    // Hanging up without a Stop is a normal end to a session: the crow is freed for the next
+    // client rather than the server falling over
+    #[tokio::test]
+    async fn hang_up_ends_the_session() {
+        let addr = spawn(DEFAULT_BIND, true).await;
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        let mut buf = Vec::new();
+        send(&mut conn, Start { version: None }).await;
+        assert!(matches!(reply(&mut conn, &mut buf).await, Affirm));
+        drop(conn);
+
+        // The session notices the hang up on its next read, which can be just after we look
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        loop {
+            let mut conn = TcpStream::connect(addr).await.unwrap();
+            let mut buf = Vec::new();
+            send(&mut conn, Start { version: None }).await;
+            match reply(&mut conn, &mut buf).await {
+                Affirm => break,
+                Failure { contents, .. } if contents == "BUSY" && Instant::now() < deadline => {
+                    tokio::time::sleep(Duration::from_millis(10)).await
+                }
+                reply => panic!("expected the crow to be free again, got {reply:?}"),
+            }
+        }
+    }
+
+    // While one client holds an exclusive server's crow, a second is turned away instead of
+    // waiting on the lock
+    #[tokio::test]
+    async fn second_client_is_busy() {
+        let addr = spawn(DEFAULT_BIND, true).await;
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        let mut first_buf = Vec::new();
+        send(&mut first, Start { version: None }).await;
+        assert!(matches!(reply(&mut first, &mut first_buf).await, Affirm));
+
+        // A Check is answered and hung up on, so each goes on a connection of its own
+        for msg in [Check { version: None }, Start { version: None }] {
+            let mut second = TcpStream::connect(addr).await.unwrap();
+            let mut buf = Vec::new();
+            send(&mut second, msg).await;
+            match reply(&mut second, &mut buf).await {
+                Failure { contents, .. } => assert_eq!(contents, "BUSY"),
+                reply => panic!("expected BUSY, got {reply:?}"),
             }
         }
     }
+
+    // The Start is acknowledged by itself, before the client has sent anything to answer
+    #[tokio::test]
+    async fn start_is_acknowledged() {
+        let addr = spawn(DEFAULT_BIND, true).await;
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        let mut buf = Vec::new();
+        send(&mut conn, Start { version: None }).await;
+        assert!(matches!(reply(&mut conn, &mut buf).await, Affirm));
+
+        send(
+            &mut conn,
+            Success {
+                request_id: 1,
+                contents: "print('after')".into(),
+            },
+        )
+        .await;
+        assert!(matches!(
+            reply(&mut conn, &mut buf).await,
+            Success { request_id: 1, ref contents } if contents == "after"
+        ));
+    }
+
+    // A client that pipelines its first request behind the Start, in the same write, has both
+    // answered
+    #[tokio::test]
+    async fn request_pipelined_behind_start() {
+        let addr = spawn(DEFAULT_BIND, true).await;
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        let mut buf = Vec::new();
+
+        let request = Success {
+            request_id: 1,
+            contents: "print(1 + 1)".into(),
+        };
+        let pipelined = framed(&[Start { version: None }, request]);
+        conn.write_all(&pipelined).await.unwrap();
+
+        assert!(matches!(reply(&mut conn, &mut buf).await, Affirm));
+        match reply(&mut conn, &mut buf).await {
+            Success {
+                request_id: 1,
+                contents,
+            } => assert_eq!(contents, "2"),
+            reply => panic!("expected the answer, got {reply:?}"),
+        }
+        assert!(matches!(
+            reply(&mut conn, &mut buf).await,
+            Done { request_id: 1 }
+        ));
+    }
 }
@@ -0,0 +1,31 @@
+use super::error::*;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::{
+    ServerConfig,
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+};
+
+fn tls_error(path: &Path, err: impl std::fmt::Display) -> Error {
+    Error::Tls(format!("{}: {err}", path.display()))
+}
+
+// Server side TLS from a PEM certificate chain and its private key, e.g. from mkcert or certbot
+pub fn acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|err| tls_error(cert, err))?;
+    let key = PrivateKeyDer::from_pem_file(key).map_err(|err| tls_error(key, err))?;
+
+    let config = ServerConfig::builder_with_provider(Arc::new(
+        tokio_rustls::rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .map_err(|err| Error::Tls(err.to_string()))?
+    .with_no_client_auth()
+    .with_single_cert(certs, key)
+    .map_err(|err| Error::Tls(err.to_string()))?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
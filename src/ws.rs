@@ -0,0 +1,176 @@
+use super::{crow::Crow, error::*, event::CrowEvent};
+use futures::{SinkExt, StreamExt};
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc};
+use tokio_tungstenite::tungstenite::{
+    Message,
+    handshake::server::{Callback, ErrorResponse, Request, Response},
+    http::StatusCode,
+};
+use tracing::{error, info};
+
+// Events a slow browser can fall behind by before it starts missing them
+const BACKLOG: usize = 256;
+
+// Broadcast every line the crow prints to each connected browser as a JSON `CrowEvent`, and send
+// text the browsers send back to the crow as Lua. Any page the user has open can reach a localhost
+// websocket, so only pages from `origins` get in, "*" lets in anything, Origin or not
+pub async fn run(crow: Crow, port: u16, origins: Vec<String>) -> Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+    println!(
+        "pidgeon websocket listening on ws://{}",
+        listener.local_addr()?
+    );
+
+    let (mut reader, mut writer) = crow.split();
+    let (events, _) = broadcast::channel::<String>(BACKLOG);
+    let (commands, mut pending) = mpsc::channel::<String>(BACKLOG);
+
+    let origins: Arc<[String]> = origins.into();
+    let broadcaster = events.clone();
+    let read = async move {
+        loop {
+            let line = reader.read_once().await?;
+            let event = serde_json::to_string(&CrowEvent::parse(&line))?;
+            // No receivers just means no browser is connected yet
+            let _ = broadcaster.send(event);
+        }
+    };
+
+    let write = async move {
+        while let Some(command) = pending.recv().await {
+            writer.write_line(&command).await?;
+        }
+        Ok(())
+    };
+
+    let accept = async move {
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            info!(target: "pidgeon::ws", "Browser connected from {addr}");
+
+            let events = events.subscribe();
+            let commands = commands.clone();
+            let origins = origins.clone();
+            tokio::spawn(async move {
+                if let Err(err) = handle_client(stream, &origins, events, commands).await {
+                    error!(target: "pidgeon::ws", "Websocket client {addr} failed: {err}");
+                }
+                info!(target: "pidgeon::ws", "Browser {addr} disconnected");
+            });
+        }
+    };
+
+    tokio::select! {
+        res = read => res,
+        res = write => res,
+        res = accept => res,
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    }
+}
+
+// Browsers always send the page's Origin, so a missing one is a script and a foreign one is some
+// other site. Either is refused before the upgrade unless it was allowed
+struct OriginCheck<'a>(&'a [String]);
+
+impl Callback for OriginCheck<'_> {
+    fn on_request(
+        self,
+        request: &Request,
+        response: Response,
+    ) -> std::result::Result<Response, ErrorResponse> {
+        let origin = request
+            .headers()
+            .get("origin")
+            .and_then(|origin| origin.to_str().ok());
+        if self
+            .0
+            .iter()
+            .any(|allowed| allowed == "*" || Some(allowed.as_str()) == origin)
+        {
+            return Ok(response);
+        }
+
+        info!(target: "pidgeon::ws", "Refused a client from origin {origin:?}");
+        let mut refused = ErrorResponse::new(Some(format!(
+            "origin {} isn't allowed, start pidgeon ws with --allow-origin",
+            origin.unwrap_or("(none)")
+        )));
+        *refused.status_mut() = StatusCode::FORBIDDEN;
+        Err(refused)
+    }
+}
+
+async fn handle_client(
+    stream: TcpStream,
+    origins: &[String],
+    mut events: broadcast::Receiver<String>,
+    commands: mpsc::Sender<String>,
+) -> Result<()> {
+    let ws = tokio_tungstenite::accept_hdr_async(stream, OriginCheck(origins))
+        .await
+        .map_err(std::io::Error::other)?;
+    let (mut sink, mut incoming) = ws.split();
+
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => sink
+                    .send(Message::text(event))
+                    .await
+                    .map_err(std::io::Error::other)?,
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    info!(target: "pidgeon::ws", "Browser fell behind, dropped {missed} event(s)");
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            msg = incoming.next() => match msg {
+                Some(Ok(Message::Text(text))) => {
+                    if commands.send(text.to_string()).await.is_err() {
+                        break;
+                    }
+                }
+                Some(Ok(Message::Close(_))) | None => break,
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Err(std::io::Error::other(err).into()),
+            },
+        }
+    }
+
+    let _ = sink.close().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from(origins: &[&str], origin: Option<&str>) -> bool {
+        let origins = origins
+            .iter()
+            .map(|origin| origin.to_string())
+            .collect::<Vec<_>>();
+        let mut request = Request::builder();
+        if let Some(origin) = origin {
+            request = request.header("Origin", origin);
+        }
+        let request = request.body(()).unwrap();
+
+        OriginCheck(&origins)
+            .on_request(&request, Response::new(()))
+            .is_ok()
+    }
+
+    #[test]
+    fn only_allowed_origins_connect() {
+        let page = "http://localhost:3000";
+        assert!(from(&[page], Some(page)));
+        assert!(!from(&[page], Some("https://example.com")));
+        assert!(!from(&[page], None));
+        assert!(!from(&[], Some(page)));
+
+        assert!(from(&["*"], Some("https://example.com")));
+        assert!(from(&["*"], None));
+    }
+}